@@ -0,0 +1,63 @@
+//! Measures the round trip time of small messages echoed by `dl1 echo` over loopback, which is
+//! dominated by the echo server itself rather than the network.
+//!
+//! Run with `cargo bench --bench loopback_rtt`. The number of messages and their length can be
+//! changed with the `RTT_MESSAGES` and `RTT_MESSAGE_LEN` environment variables.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::env;
+use std::io::{ Read, Write };
+use std::net::{ TcpStream, UdpSocket };
+use std::time::{ Duration, Instant };
+
+use common::EchoServer;
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn report(name: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    let micros = |d: Duration| d.as_secs_f64() * 1_000_000.0;
+    let mean = samples.iter().map(|d| micros(*d)).sum::<f64>() / samples.len() as f64;
+    let percentile = |p: f64| micros(samples[((samples.len() - 1) as f64 * p).round() as usize]);
+    println!("{:<4} n={:<6} mean={:>9.1}us  median={:>9.1}us  p99={:>9.1}us  max={:>9.1}us",
+             name, samples.len(), mean, percentile(0.5), percentile(0.99), micros(samples[samples.len() - 1]));
+}
+
+fn tcp_rtt(mut stream: TcpStream, messages: usize, message_len: usize) -> Vec<Duration> {
+    stream.set_nodelay(true).unwrap();
+    let message = vec![7u8; message_len];
+    let mut reply = vec![0u8; message_len];
+    (0..messages).map(|_| {
+        let start = Instant::now();
+        stream.write_all(&message).unwrap();
+        stream.read_exact(&mut reply).unwrap();
+        start.elapsed()
+    }).collect()
+}
+
+fn udp_rtt(udp_port: u16, messages: usize, message_len: usize) -> Vec<Duration> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(("127.0.0.1", udp_port)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let message = vec![7u8; message_len];
+    let mut reply = vec![0u8; message_len];
+    (0..messages).filter_map(|_| {
+        let start = Instant::now();
+        socket.send(&message).unwrap();
+        socket.recv(&mut reply).ok().map(|_| start.elapsed())
+    }).collect()
+}
+
+fn main() {
+    let messages = env_or("RTT_MESSAGES", 2000);
+    let message_len = env_or("RTT_MESSAGE_LEN", 64);
+    let server = EchoServer::start(&[]);
+
+    println!("loopback round trip time, {} byte messages", message_len);
+    report("tcp", tcp_rtt(server.connect(), messages, message_len));
+    report("udp", udp_rtt(server.udp_port, messages, message_len));
+}
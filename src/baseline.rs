@@ -0,0 +1,250 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{ BufReader, Write, self };
+use std::time::SystemTime;
+
+use serde_json;
+
+use compare::{ self, format_rate, format_secs, percent_change, TestKey };
+use output::unix_ms;
+use stats::mann_whitney_u;
+use test::*;
+
+/// The file `baseline save` writes to and `baseline check` reads if one isn't given.
+pub const DEFAULT_BASELINE_FILE: &str = "baseline.json";
+
+/// Results that later runs are checked against, to find out when they get worse.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Baseline {
+    /// The version of this program that saved the baseline
+    pub version: String,
+    /// The results file the baseline was saved from
+    pub source: String,
+    pub saved_unix_ms: u64,
+    pub tests: Vec<TestData>,
+}
+
+impl Baseline {
+    pub fn new(source: &str, tests: Vec<TestData>) -> Baseline {
+        Baseline {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            source: source.to_string(),
+            saved_unix_ms: unix_ms(SystemTime::now()),
+            tests,
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), io::Error> {
+        let mut file = File::create(filename)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")
+    }
+
+    pub fn load(filename: &str) -> Result<Baseline, io::Error> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(filename)?))?)
+    }
+}
+
+/// How much worse each metric of a test can get before it counts as a regression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// How much longer the median round trip time can get, in percent
+    pub median_rtt_percent: f64,
+    /// How much longer the 99th percentile round trip time can get, in percent
+    pub p99_rtt_percent: f64,
+    /// How much the percentage of messages that were dropped can grow, in percentage points
+    pub loss_points: f64,
+    /// How much lower the throughput can get, in percent
+    pub throughput_percent: f64,
+    /// Round trip times that got longer only count as a regression if a Mann-Whitney U test
+    /// finds they're longer with a p-value under this, when both runs echoed enough messages
+    pub alpha: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds { median_rtt_percent: 10.0, p99_rtt_percent: 25.0, loss_points: 1.0, throughput_percent: 10.0, alpha: 0.05 }
+    }
+}
+
+/// How a metric of a test compares with the baseline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Ok,
+    /// Worse by more than the threshold, but the Mann-Whitney U test couldn't tell the round
+    /// trip times apart
+    Noise,
+    Regressed,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Verdict::Ok => write!(f, "ok"),
+            Verdict::Noise => write!(f, "ok (not significant)"),
+            Verdict::Regressed => write!(f, "REGRESSED"),
+        }
+    }
+}
+
+/// One metric of one test, checked against the baseline.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub test: TestKey,
+    pub metric: &'static str,
+    pub baseline: String,
+    pub current: String,
+    pub change: String,
+    pub limit: String,
+    pub p_value: Option<f64>,
+    pub verdict: Verdict,
+}
+
+/// The result of checking a run against a baseline.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// Tests in the baseline the run doesn't have, which count as regressions
+    pub missing: Vec<TestKey>,
+    /// Tests in the run the baseline doesn't have, which can't be checked
+    pub new: Vec<TestKey>,
+}
+
+impl Report {
+    /// Every metric that regressed, along with every test that's missing.
+    pub fn regressions(&self) -> Vec<String> {
+        self.findings.iter()
+            .filter(|finding| finding.verdict == Verdict::Regressed)
+            .map(|finding| format!("{} {}", finding.test, finding.metric))
+            .chain(self.missing.iter().map(|test| format!("{} is missing", test)))
+            .collect()
+    }
+}
+
+/// Checks the tests in [current] against those in [baseline], which are aligned the same way
+/// `compare` aligns them.
+pub fn check(baseline: &[TestData], current: &[TestData], thresholds: &Thresholds) -> Report {
+    let mut report = Report::default();
+    for (test, in_files) in compare::align(&[baseline, current]) {
+        match (in_files[0], in_files[1]) {
+            (Some(before), Some(after)) => check_test(&mut report, test, before, after, thresholds),
+            (Some(_), None) => report.missing.push(test),
+            (None, _) => report.new.push(test),
+        }
+    }
+    report
+}
+
+fn check_test(report: &mut Report, test: TestKey, before: &TestData, after: &TestData, thresholds: &Thresholds) {
+    let p_value = mann_whitney_u(&before.round_trip_times(), &after.round_trip_times());
+    let finding = |metric, baseline, current, change, limit, p_value, verdict| Finding {
+        test: test.clone(), metric, baseline, current, change, limit, p_value, verdict,
+    };
+    let percent = |change: Option<f64>| change.map_or("n/a".to_string(), |change| format!("{:+.1}%", change));
+
+    if let (Some(before_latency), Some(after_latency)) = (before.latency_stats(), after.latency_stats()) {
+        let rtts = [
+            ("median RTT", before_latency.median, after_latency.median, thresholds.median_rtt_percent),
+            ("p99 RTT", before_latency.p99, after_latency.p99, thresholds.p99_rtt_percent),
+        ];
+        for &(metric, before_rtt, after_rtt, limit) in rtts.iter() {
+            let change = percent_change(before_rtt.as_secs_f64(), after_rtt.as_secs_f64());
+            let verdict = match change {
+                Some(change) if change > limit && p_value.is_some_and(|p| p >= thresholds.alpha) => Verdict::Noise,
+                Some(change) if change > limit => Verdict::Regressed,
+                _ => Verdict::Ok,
+            };
+            report.findings.push(finding(metric, format_secs(before_rtt.as_secs_f64()), format_secs(after_rtt.as_secs_f64()),
+                                         percent(change), format!("+{:.1}%", limit), p_value, verdict));
+        }
+    }
+
+    let (before_loss, after_loss) = (before.loss_percent(), after.loss_percent());
+    let verdict = if after_loss - before_loss > thresholds.loss_points { Verdict::Regressed } else { Verdict::Ok };
+    report.findings.push(finding("loss", format!("{:.2}%", before_loss), format!("{:.2}%", after_loss),
+                                 format!("{:+.2} pts", after_loss - before_loss), format!("+{:.2} pts", thresholds.loss_points), None, verdict));
+
+    if let (Some(before_throughput), Some(after_throughput)) = (before.summary().throughput, after.summary().throughput) {
+        let change = percent_change(before_throughput, after_throughput);
+        let verdict = if change.is_some_and(|change| change < -thresholds.throughput_percent) { Verdict::Regressed } else { Verdict::Ok };
+        report.findings.push(finding("throughput", format_rate(before_throughput), format_rate(after_throughput),
+                                     percent(change), format!("-{:.1}%", thresholds.throughput_percent), None, verdict));
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = ["test", "metric", "baseline", "current", "change", "limit", "p-value", "result"];
+        let mut rows: Vec<Vec<String>> = vec![header.iter().map(|cell| cell.to_string()).collect()];
+        for finding in self.findings.iter() {
+            rows.push(vec![
+                finding.test.to_string(),
+                finding.metric.to_string(),
+                finding.baseline.clone(),
+                finding.current.clone(),
+                finding.change.clone(),
+                finding.limit.clone(),
+                finding.p_value.map_or("-".to_string(), |p| format!("{:.4}", p)),
+                finding.verdict.to_string(),
+            ]);
+        }
+        for test in self.missing.iter() {
+            rows.push(vec![test.to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "MISSING".to_string()]);
+        }
+        for test in self.new.iter() {
+            rows.push(vec![test.to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "-".to_string(), "not in baseline".to_string()]);
+        }
+
+        let mut widths = vec![0; header.len()];
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in rows.iter() {
+            let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+            writeln!(f, "  {}", cells.join("   ").trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Duration;
+
+    use error::{ DroppedMessage, TestError };
+    use import;
+    use output::{ self, TestRecord };
+
+    /// A UDP test of 10 messages that echoed every one but [dropped].
+    fn udp_test(dropped: &[u32]) -> TestData {
+        let durations = (0..10).map(|i| if dropped.contains(&i) { None } else { Some(Duration::from_millis(2)) }).collect();
+        let dropped_messages = dropped.iter().map(|&i| DroppedMessage::new(i, &TestError::Timeout)).collect();
+        let spec = TestSpec { num_messages: 10, message_len: 64, ..TestSpec::default() };
+        TestData::new(Test::UdpTest(spec), Duration::from_millis(20), durations, dropped_messages)
+    }
+
+    #[test]
+    fn loss_survives_csv() {
+        let filename = env::temp_dir().join(format!("dl1-loss-{}.csv", process::id())).to_string_lossy().into_owned();
+        let lossy = udp_test(&[3, 7]);
+        let written = output::save_csv(&[TestRecord::new(0, None, lossy.test.clone(), Ok(lossy))], "run", &filename).unwrap();
+        let loaded = import::load(&filename);
+        for file in written.iter().chain(Some(&filename)) {
+            let _ = fs::remove_file(file);
+        }
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded[0].loss_percent(), 20.0);
+
+        let report = check(&[udp_test(&[])], &loaded, &Thresholds::default());
+        let loss = report.findings.iter().find(|finding| finding.metric == "loss").unwrap();
+        assert_eq!(loss.change, "+20.00 pts");
+        assert_eq!(loss.verdict, Verdict::Regressed);
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap::{ App, AppSettings, Arg, ArgMatches, SubCommand };
+
+use baseline::{ Thresholds, DEFAULT_BASELINE_FILE };
+use config::{ Config, ConfigError, SETTINGS };
+use echo::EchoOptions;
+use output::Format;
+use test::*;
+use util::{ VERBOSITY_QUIET, VERBOSITY_NORMAL, VERBOSITY_DEBUG };
+
+pub const ECHO: &str = "echo";
+pub const TEST: &str = "test";
+pub const REQ_DATA: &str = "required";
+pub const RUN: &str = "run";
+pub const CONFIG: &str = "config";
+pub const CONFIG_SHOW: &str = "show";
+pub const COMPARE: &str = "compare";
+pub const BASELINE: &str = "baseline";
+pub const BASELINE_SAVE: &str = "save";
+pub const BASELINE_CHECK: &str = "check";
+
+/// The name of the file `required` writes its results to if one isn't given, before the extension of the format.
+pub const DEFAULT_OUTPUT_NAME: &str = "data";
+
+const SPEC_HELP: &str = "Tests to run, in the form PROTOCOL:NUM_MESSAGES:MESSAGE_LEN (e.g. tcp:64:1024)";
+
+/// Builds the command line interface.
+pub fn app() -> App<'static, 'static> {
+    let mut app = App::new("dl1")
+        .about("Measures round trip time and throughput of TCP and UDP through an echo server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .takes_value(true)
+            .global(true)
+            .help("TOML config file (default: $DL1_CONFIG, or dl1.toml if it exists)"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .global(true)
+            .help("Print debug output, such as every echoed message"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .global(true)
+            .conflicts_with("verbose")
+            .help("Only print errors"));
+
+    for setting in SETTINGS {
+        app = app.arg(Arg::with_name(setting.key)
+            .long(setting.flag)
+            .value_name("VALUE")
+            .takes_value(true)
+            .global(true)
+            .help(setting.help));
+    }
+
+    app.subcommand(SubCommand::with_name(ECHO)
+            .about("Runs the echo server until enter is pressed, or until it receives SIGINT or SIGTERM")
+            .arg(Arg::with_name("no-stdin")
+                .long("no-stdin")
+                .help("Run in the foreground without reading standard input, only stopping on SIGINT or SIGTERM"))
+            .arg(Arg::with_name("daemon")
+                .long("daemon")
+                .conflicts_with("no-stdin")
+                .help("Run in the background, only stopping on SIGINT or SIGTERM"))
+            .arg(Arg::with_name("pid-file")
+                .long("pid-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Write the echo server's process id to this file, which is removed when the server stops"))
+            .arg(Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("daemon")
+                .help("Append the daemon's output to this file instead of discarding it")))
+        .subcommand(SubCommand::with_name(TEST)
+            .about("Runs the given tests against the echo server")
+            .after_help("On the wire, every TCP message is sent with a 12 byte header (a magic number, the message \
+                         number and the payload length) in front of it, so its echo can be found again after a \
+                         timeout. Every UDP datagram starts with a 16 byte probe header, and shorter messages are \
+                         padded to that length.")
+            .arg(Arg::with_name("spec")
+                .value_name("SPEC")
+                .multiple(true)
+                .help(SPEC_HELP))
+            .arg(Arg::with_name("protocol")
+                .short("p")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .takes_value(true)
+                .possible_values(&["tcp", "udp"])
+                .help("Run a single test using this protocol"))
+            .arg(Arg::with_name("count")
+                .short("n")
+                .long("count")
+                .value_name("COUNT")
+                .takes_value(true)
+                .requires("protocol")
+                .help("The number of messages the test given by --protocol sends [default: 64]"))
+            .arg(Arg::with_name("size")
+                .short("s")
+                .long("size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("protocol")
+                .help("The length in bytes of each message the test given by --protocol sends [default: 1024]"))
+            .arg(timeout_arg())
+            .arg(Arg::with_name("window")
+                .short("w")
+                .long("window")
+                .value_name("COUNT")
+                .takes_value(true)
+                .help("Keep up to COUNT messages of each UDP test in flight at once, instead of waiting for each echo"))
+            .arg(Arg::with_name("bitrate")
+                .short("b")
+                .long("bitrate")
+                .value_name("RATE")
+                .takes_value(true)
+                .conflicts_with("pps")
+                .help("Send the messages of each UDP test at RATE bits per second, with an optional K, M or G suffix (e.g. 10M)"))
+            .arg(Arg::with_name("pps")
+                .long("pps")
+                .value_name("RATE")
+                .takes_value(true)
+                .help("Send the messages of each UDP test at RATE messages per second"))
+            .arg(Arg::with_name("duration")
+                .long("duration")
+                .value_name("MS")
+                .takes_value(true)
+                .help("Stream data through the echo server for MS milliseconds in each TCP test, in chunks of MESSAGE_LEN bytes, instead of echoing separate messages"))
+            .arg(Arg::with_name("bytes")
+                .long("bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Stream BYTES bytes through the echo server in each TCP test, in chunks of MESSAGE_LEN bytes, instead of echoing separate messages"))
+            .arg(Arg::with_name("parallel")
+                .short("P")
+                .long("parallel")
+                .value_name("STREAMS")
+                .takes_value(true)
+                .help("Run each test over STREAMS connections (or sockets) at once"))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("MS")
+                .takes_value(true)
+                .help("How often tests given --duration or --bytes sample their throughput, in milliseconds [default: 1000]"))
+            .arg(Arg::with_name("direction")
+                .short("d")
+                .long("direction")
+                .value_name("DIRECTION")
+                .takes_value(true)
+                .possible_values(&["echo", "reverse", "bidirectional"])
+                .help("Which way tests given --duration or --bytes stream: through the echo server and back, from the echo server to here, or both ways at once [default: echo]"))
+            .arg(Arg::with_name("connect-per-message")
+                .long("connect-per-message")
+                .help("Open a new connection for every message of each TCP test, timing how long connecting and closing take"))
+            .arg(output_arg())
+            .arg(format_arg()))
+        .subcommand(SubCommand::with_name(REQ_DATA)
+            .about("Runs the tests required for the assignment")
+            .arg(output_arg()
+                .help("File to write the results to [default: data followed by the format's extension, e.g. data.csv]"))
+            .arg(format_arg()))
+        .subcommand(SubCommand::with_name(RUN)
+            .about("Runs every test in a suite file")
+            .arg(Arg::with_name("suite")
+                .long("suite")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .help("TOML (or .json) file containing the suite's name and a list of tests"))
+            .arg(output_arg()
+                .help("File to write the results to (default: the suite's name followed by the format's extension, e.g. .csv)"))
+            .arg(format_arg()))
+        .subcommand(SubCommand::with_name(CONFIG)
+            .about("Inspects the configuration")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name(CONFIG_SHOW)
+                .about("Prints the effective settings and where each one came from")))
+        .subcommand(SubCommand::with_name(COMPARE)
+            .about("Compares the latency and throughput of the tests in saved results, by protocol, message length and number of messages")
+            .arg(Arg::with_name("files")
+                .value_name("FILE")
+                .multiple(true)
+                .min_values(2)
+                .required(true)
+                .help("Results saved in any format, including CSV from older versions. The first is the baseline the others are compared with")))
+        .subcommand(SubCommand::with_name(BASELINE)
+            .about("Saves results as a baseline, and checks later results against it for regressions")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name(BASELINE_SAVE)
+                .about("Saves the results in a file as the baseline")
+                .arg(results_arg())
+                .arg(baseline_arg()))
+            .subcommand(SubCommand::with_name(BASELINE_CHECK)
+                .about("Checks the results in a file against the baseline, exiting with 3 if any test got worse than the thresholds allow")
+                .arg(results_arg())
+                .arg(baseline_arg())
+                .arg(threshold_arg("max-median-rtt-increase", "PERCENT", "How much longer the median round trip time of a test can get, in percent [default: 10]"))
+                .arg(threshold_arg("max-p99-rtt-increase", "PERCENT", "How much longer the 99th percentile round trip time of a test can get, in percent [default: 25]"))
+                .arg(threshold_arg("max-loss-increase", "POINTS", "How much the percentage of messages a test dropped can grow, in percentage points [default: 1]"))
+                .arg(threshold_arg("max-throughput-decrease", "PERCENT", "How much lower the throughput of a test can get, in percent [default: 10]"))
+                .arg(threshold_arg("alpha", "P", "Round trip times that got longer only count as a regression if a Mann-Whitney U test finds they're longer with a p-value under P, when both runs echoed at least 8 messages [default: 0.05]"))))
+}
+
+fn results_arg() -> Arg<'static, 'static> {
+    Arg::with_name("results")
+        .value_name("FILE")
+        .required(true)
+        .help("Results saved in any format, including CSV from older versions")
+}
+
+fn baseline_arg() -> Arg<'static, 'static> {
+    Arg::with_name("baseline")
+        .long("baseline")
+        .value_name("FILE")
+        .takes_value(true)
+        .default_value(DEFAULT_BASELINE_FILE)
+        .help("The baseline file")
+}
+
+fn threshold_arg(name: &'static str, value_name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name(value_name)
+        .takes_value(true)
+        .help(help)
+}
+
+fn timeout_arg() -> Arg<'static, 'static> {
+    Arg::with_name("timeout")
+        .short("t")
+        .long("timeout")
+        .value_name("MS")
+        .takes_value(true)
+        .help("How long to wait for each message to be echoed, in milliseconds. Overrides --timeout-ms for these tests.")
+}
+
+fn output_arg() -> Arg<'static, 'static> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("File to write the results to. As CSV this is a summary of each test, and every message is written to FILE.samples.csv next to it (with .csv left out of FILE)")
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .value_name("FORMAT")
+        .takes_value(true)
+        .possible_values(&["csv", "json", "jsonl"])
+        .default_value("csv")
+        .help("Write the results as CSV files, one JSON document, or JSON Lines with a record for every message as soon as it completes")
+}
+
+/// Resolves the configuration using the config file and setting flags in [matches].
+pub fn config(matches: &ArgMatches) -> Result<Config, ConfigError> {
+    let flags: BTreeMap<String, String> = SETTINGS.iter()
+        .filter_map(|s| matches.value_of(s.key).map(|v| (s.key.to_string(), v.to_string())))
+        .collect();
+    Config::load(matches.value_of("config"), &flags)
+}
+
+pub fn verbosity(matches: &ArgMatches) -> usize {
+    if matches.is_present("quiet") {
+        VERBOSITY_QUIET
+    } else if matches.is_present("verbose") {
+        VERBOSITY_DEBUG
+    } else {
+        VERBOSITY_NORMAL
+    }
+}
+
+/// How the `echo` subcommand should run the echo server.
+pub fn echo_options(matches: &ArgMatches) -> EchoOptions {
+    let daemon = matches.is_present("daemon");
+    EchoOptions {
+        read_stdin: !daemon && !matches.is_present("no-stdin"),
+        daemon,
+        pid_file: matches.value_of("pid-file").map(PathBuf::from),
+        log_file: matches.value_of("log-file").map(PathBuf::from),
+    }
+}
+
+/// Parses [name]'s value, if it is present.
+fn optional_number<T: ::std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String> {
+    match matches.value_of(name) {
+        Some(value) => parse_number(name, value).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The format results should be written in.
+pub fn format(matches: &ArgMatches) -> Format {
+    matches.value_of("format").and_then(|format| format.parse().ok()).unwrap_or_default()
+}
+
+/// The thresholds given to `baseline check`, with the default for any that aren't.
+pub fn thresholds(matches: &ArgMatches) -> Result<Thresholds, String> {
+    let defaults = Thresholds::default();
+    let thresholds = Thresholds {
+        median_rtt_percent: optional_number(matches, "max-median-rtt-increase")?.unwrap_or(defaults.median_rtt_percent),
+        p99_rtt_percent: optional_number(matches, "max-p99-rtt-increase")?.unwrap_or(defaults.p99_rtt_percent),
+        loss_points: optional_number(matches, "max-loss-increase")?.unwrap_or(defaults.loss_points),
+        throughput_percent: optional_number(matches, "max-throughput-decrease")?.unwrap_or(defaults.throughput_percent),
+        alpha: optional_number(matches, "alpha")?.unwrap_or(defaults.alpha),
+    };
+    if !(thresholds.alpha > 0.0 && thresholds.alpha < 1.0) {
+        return Err(format!("'{}' is not a valid number for alpha. It must be between 0 and 1.", thresholds.alpha))
+    }
+    Ok(thresholds)
+}
+
+/// The options given to the `test` subcommand that apply to every test, as a spec with no
+/// messages that each test's spec is built on.
+fn spec_options(matches: &ArgMatches) -> Result<TestSpec, String> {
+    Ok(TestSpec {
+        timeout_ms: optional_number(matches, "timeout")?,
+        window: optional_number(matches, "window")?,
+        rate_bps: match matches.value_of("bitrate") {
+            Some(rate) => Some(parse_bitrate(rate)?),
+            None => None,
+        },
+        rate_pps: optional_number(matches, "pps")?,
+        duration_ms: optional_number(matches, "duration")?,
+        total_bytes: optional_number(matches, "bytes")?,
+        interval_ms: optional_number(matches, "interval")?,
+        parallel: optional_number(matches, "parallel")?,
+        direction: match matches.value_of("direction") {
+            Some(direction) => direction.parse()?,
+            None => Direction::Echo,
+        },
+        connect_per_message: matches.is_present("connect-per-message"),
+        ..TestSpec::default()
+    })
+}
+
+/// Parses every test given to the `test` subcommand, both as SPECs and with `--protocol`. Every
+/// test is validated, and the first invalid one is reported as an error.
+pub fn tests(matches: &ArgMatches) -> Result<Vec<Test>, String> {
+    let options = spec_options(matches)?;
+    let mut tests = vec![];
+
+    for spec in matches.values_of("spec").into_iter().flatten() {
+        tests.push(parse_spec(spec, &options)?);
+    }
+
+    if let Some(protocol) = matches.value_of("protocol") {
+        let spec = TestSpec {
+            num_messages: parse_number("count", matches.value_of("count").unwrap_or("64"))?,
+            message_len: parse_number("size", matches.value_of("size").unwrap_or("1024"))?,
+            ..options
+        };
+        tests.push(Test::new(protocol.parse()?, spec));
+    }
+
+    if tests.is_empty() {
+        return Err("No tests provided. Give at least one SPEC or use --protocol.".to_string())
+    }
+
+    for test in tests.iter() {
+        test.validate().map_err(|e| format!("'{}' is not a valid test: {}.", test, e))?;
+    }
+    Ok(tests)
+}
+
+/// Parses a test of the form PROTOCOL:NUM_MESSAGES:MESSAGE_LEN, with the rest of its spec taken
+/// from [options].
+fn parse_spec(s: &str, options: &TestSpec) -> Result<Test, String> {
+    let tokens: Vec<&str> = s.split(':').collect();
+    if tokens.len() != 3 {
+        return Err(format!("'{}' is not a valid test. {}.", s, SPEC_HELP))
+    }
+
+    let spec = TestSpec {
+        num_messages: parse_number("NUM_MESSAGES", tokens[1])?,
+        message_len: parse_number("MESSAGE_LEN", tokens[2])?,
+        ..options.clone()
+    };
+    Ok(Test::new(tokens[0].parse()?, spec))
+}
+
+/// Parses a number of bits per second, which can end in K, M or G to multiply it by a thousand,
+/// million or billion.
+fn parse_bitrate(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1e3),
+        Some('M') => (&value[..value.len() - 1], 1e6),
+        Some('G') => (&value[..value.len() - 1], 1e9),
+        _ => (value, 1.0),
+    };
+    let number: f64 = parse_number("bitrate", number)?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("'{}' is not a valid number for bitrate.", value))
+    }
+    Ok((number * multiplier).round() as u64)
+}
+
+fn parse_number<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("'{}' is not a valid number for {}.", value, name))
+}
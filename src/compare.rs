@@ -0,0 +1,187 @@
+use std::fmt;
+
+use test::*;
+
+/// Formats a time in seconds with a unit that suits it, like `1.574 s` or `-21.700 ms`.
+pub fn format_secs(secs: f64) -> String {
+    match secs.abs() {
+        abs if abs >= 1.0 => format!("{:.3} s", secs),
+        abs if abs >= 1e-3 => format!("{:.3} ms", secs * 1e3),
+        _ => format!("{:.3} µs", secs * 1e6),
+    }
+}
+
+/// Formats a rate in bytes per second with a unit that suits it, like `650.609 B/s` or `4.452 MB/s`.
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    match bytes_per_sec.abs() {
+        abs if abs >= 1e9 => format!("{:.3} GB/s", bytes_per_sec / 1e9),
+        abs if abs >= 1e6 => format!("{:.3} MB/s", bytes_per_sec / 1e6),
+        abs if abs >= 1e3 => format!("{:.3} kB/s", bytes_per_sec / 1e3),
+        _ => format!("{:.3} B/s", bytes_per_sec),
+    }
+}
+
+/// How much [value] changed from [baseline], in percent. None if the baseline is 0.
+pub fn percent_change(baseline: f64, value: f64) -> Option<f64> {
+    if baseline == 0.0 { None } else { Some((value - baseline) * 100.0 / baseline) }
+}
+
+/// What tests are aligned by across files: the same protocol, message length and number of
+/// messages. The nth test with the same key in one file is compared with the nth in the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestKey {
+    protocol: Protocol,
+    message_len: usize,
+    messages: usize,
+    occurrence: usize,
+}
+
+/// Aligns the tests in every one of [files], giving each test with the results it has in each
+/// file, if the file has it. Tests are in the order they first appear in.
+pub fn align<'a>(files: &[&'a [TestData]]) -> Vec<(TestKey, Vec<Option<&'a TestData>>)> {
+    let mut tests: Vec<(TestKey, Vec<Option<&'a TestData>>)> = vec![];
+    for (file, results) in files.iter().enumerate() {
+        let mut seen: Vec<TestKey> = vec![];
+        for data in results.iter() {
+            let mut key = TestKey {
+                protocol: data.test.protocol(),
+                message_len: data.test.spec().message_len,
+                messages: data.individual_durations.len(),
+                occurrence: 0,
+            };
+            key.occurrence = seen.iter().filter(|other| TestKey { occurrence: 0, ..(*other).clone() } == key).count();
+            seen.push(key.clone());
+
+            match tests.iter_mut().find(|&&mut (ref other, _)| *other == key) {
+                Some(&mut (_, ref mut in_files)) => in_files[file] = Some(data),
+                None => {
+                    let mut in_files = vec![None; files.len()];
+                    in_files[file] = Some(data);
+                    tests.push((key, in_files));
+                },
+            }
+        }
+    }
+    tests
+}
+
+impl fmt::Display for TestKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.protocol, self.messages, self.message_len)?;
+        if self.occurrence > 0 {
+            write!(f, " (#{})", self.occurrence + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// A statistic that is compared between files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Metric {
+    MeanRtt,
+    MedianRtt,
+    P99Rtt,
+    Throughput,
+}
+
+impl Metric {
+    const ALL: [Metric; 4] = [Metric::MeanRtt, Metric::MedianRtt, Metric::P99Rtt, Metric::Throughput];
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Metric::MeanRtt => "mean RTT",
+            Metric::MedianRtt => "median RTT",
+            Metric::P99Rtt => "p99 RTT",
+            Metric::Throughput => "throughput",
+        }
+    }
+
+    fn of(&self, data: &TestData) -> Option<f64> {
+        let latency = data.latency_stats();
+        match *self {
+            Metric::MeanRtt => latency.map(|latency| latency.mean.as_secs_f64()),
+            Metric::MedianRtt => latency.map(|latency| latency.median.as_secs_f64()),
+            Metric::P99Rtt => latency.map(|latency| latency.p99.as_secs_f64()),
+            Metric::Throughput => data.summary().throughput,
+        }
+    }
+
+    fn format(&self, value: f64) -> String {
+        match *self {
+            Metric::Throughput => format_rate(value),
+            _ => format_secs(value),
+        }
+    }
+}
+
+/// The tests of two or more result files, side by side. The first file is the baseline every
+/// other one is compared with.
+pub struct Comparison<'a> {
+    files: Vec<&'a str>,
+    /// Every test, with its results in each file, if the file has it
+    tests: Vec<(TestKey, Vec<Option<&'a TestData>>)>,
+}
+
+impl<'a> Comparison<'a> {
+    /// Aligns the tests of every file in [files], which are pairs of names and results.
+    pub fn new(files: &'a [(String, Vec<TestData>)]) -> Comparison<'a> {
+        let results: Vec<&[TestData]> = files.iter().map(|(_, results)| results.as_slice()).collect();
+        Comparison { files: files.iter().map(|(name, _)| name.as_str()).collect(), tests: align(&results) }
+    }
+
+    /// The table of one test: a row for each metric, with the value in each file, and how much
+    /// it changed from the baseline.
+    fn rows(&self, in_files: &[Option<&TestData>]) -> Vec<Vec<String>> {
+        Metric::ALL.iter().map(|metric| {
+            let baseline = in_files[0].and_then(|data| metric.of(data));
+            let mut row = vec![metric.name().to_string()];
+            row.extend(in_files.iter().enumerate().map(|(i, data)| {
+                match (data.and_then(|data| metric.of(data)), baseline) {
+                    (None, _) => "-".to_string(),
+                    (Some(value), Some(baseline)) if i > 0 => {
+                        let change = percent_change(baseline, value).map_or("n/a".to_string(), |change| format!("{:+.1}%", change));
+                        let delta = metric.format(value - baseline);
+                        let delta = if delta.starts_with('-') { delta } else { format!("+{}", delta) };
+                        format!("{} ({}, {})", metric.format(value), delta, change)
+                    },
+                    (Some(value), _) => metric.format(value),
+                }
+            }));
+            row
+        }).collect()
+    }
+}
+
+impl<'a> fmt::Display for Comparison<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut header = vec!["".to_string()];
+        header.extend(self.files.iter().map(|file| file.to_string()));
+        let tables: Vec<(&TestKey, Vec<Vec<String>>)> = self.tests.iter().map(|(key, in_files)| (key, self.rows(in_files))).collect();
+
+        // Every table lines up with the others
+        let mut widths: Vec<usize> = header.iter().map(|cell| cell.chars().count()).collect();
+        for (_, rows) in tables.iter() {
+            for row in rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+        }
+        let write_row = |f: &mut fmt::Formatter, row: &[String]| -> fmt::Result {
+            let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+            writeln!(f, "  {}", cells.join("   ").trim_end())
+        };
+
+        for (i, &(key, ref rows)) in tables.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", key)?;
+            write_row(f, &header)?;
+            for row in rows {
+                write_row(f, row)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,359 @@
-// Elementary os localhost ip = 129.3.147.51
-//const TCP_IP: &'static str = "129.3.127.216:2710";
-pub const UDP_IP: &'static str = "129.3.121.23:2710";
-pub const HANDSHAKE_MSG: &'static [u8] = b"HANDSHAKE";
-pub const ECHO_SERVER_UDP_IP: &'static str = "129.3.20.24:2710";
-pub const ECHO_SERVER_TCP_IP: &'static str = "129.3.20.24:12710";
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{ Read, self };
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use toml;
+
+use util::{ create_address, pretty_print, Network };
+
+/// The config file that is read if one isn't specified explicitly (only if it exists).
+pub const DEFAULT_CONFIG_FILE: &str = "dl1.toml";
+
+/// Environment variable that can be used to specify the config file.
+pub const CONFIG_FILE_ENV: &str = "DL1_CONFIG";
+
+/// Prefix of the environment variables that can be used to override settings.
+const ENV_PREFIX: &str = "DL1_";
+
+/// A single configurable setting. [key] is used in the config file, and is used to derive the name
+/// of the environment variable (`udp_ip` becomes `DL1_UDP_IP`). [flag] is the command line flag.
+pub struct Setting {
+    pub key: &'static str,
+    pub flag: &'static str,
+    pub default: &'static str,
+    pub help: &'static str,
+}
+
+impl Setting {
+    pub fn env_var(&self) -> String {
+        format!("{}{}", ENV_PREFIX, self.key.to_uppercase())
+    }
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "udp_ip",
+        flag: "udp-ip",
+        default: "129.3.121.23:2710",
+        help: "Local address the client binds its UDP socket to",
+    },
+    Setting {
+        key: "echo_server_udp_ip",
+        flag: "echo-server-udp-ip",
+        default: "129.3.20.24:2710",
+        help: "UDP address of the echo server",
+    },
+    Setting {
+        key: "echo_server_tcp_ip",
+        flag: "echo-server-tcp-ip",
+        default: "129.3.20.24:12710",
+        help: "TCP address of the echo server",
+    },
+    Setting {
+        key: "timeout_ms",
+        flag: "timeout-ms",
+        default: "10000",
+        help: "How long to wait for a message to be echoed before it counts as dropped, in milliseconds",
+    },
+    Setting {
+        key: "echo_allowed_clients",
+        flag: "echo-allowed-clients",
+        default: "",
+        help: "Comma separated networks (e.g. 129.3.0.0/16,127.0.0.1) the UDP echo server will echo to. Empty allows everyone",
+    },
+    Setting {
+        key: "echo_max_connections",
+        flag: "echo-max-connections",
+        default: "64",
+        help: "The most TCP clients the echo server will serve at once",
+    },
+    Setting {
+        key: "echo_idle_timeout_ms",
+        flag: "echo-idle-timeout-ms",
+        default: "0",
+        help: "Close TCP connections to the echo server that are idle for this long, in milliseconds. 0 never closes them",
+    },
+    Setting {
+        key: "echo_tcp_chunk_size",
+        flag: "echo-tcp-chunk-size",
+        default: "65536",
+        help: "How many bytes the TCP echo server reads from a connection at once. Each busy connection holds one buffer this size",
+    },
+];
+
+/// The largest chunk size the TCP echo server can be configured to use.
+const MAX_TCP_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where the value of a setting came from. Later layers override earlier ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref path) => write!(f, "config file {}", path),
+            Source::Env(ref var) => write!(f, "environment variable {}", var),
+            Source::Flag(ref flag) => write!(f, "flag --{}", flag),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read
+    Io(String, io::Error),
+    /// The config file isn't valid TOML
+    Parse(String, String),
+    /// The config file contains a key that isn't a setting
+    UnknownKey(String, String),
+    /// A setting has a value that isn't valid
+    Invalid { key: &'static str, value: String, source: Source, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) =>
+                write!(f, "failed to read config file '{}': {}", path, e),
+            ConfigError::Parse(ref path, ref e) =>
+                write!(f, "failed to parse config file '{}': {}", path, e),
+            ConfigError::UnknownKey(ref path, ref key) =>
+                write!(f, "unknown setting '{}' in config file '{}'", key, path),
+            ConfigError::Invalid { key, ref value, ref source, ref reason } =>
+                write!(f, "invalid value '{}' for {} (from {}): {}", value, key, source, reason),
+        }
+    }
+}
+
+/// The fully resolved configuration. Settings are layered, with each layer overriding the one
+/// before it: built in defaults, the config file, environment variables, then command line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Local address the client binds its UDP socket to
+    pub udp_ip: SocketAddr,
+    /// UDP address of the echo server
+    pub echo_server_udp_ip: SocketAddr,
+    /// TCP address of the echo server
+    pub echo_server_tcp_ip: SocketAddr,
+    /// How long to wait for a message to be echoed, unless a test specifies its own timeout
+    pub timeout: Duration,
+    /// The networks the UDP echo server will echo datagrams to. If this is empty, every peer is allowed
+    pub echo_allowed_clients: Vec<Network>,
+    /// The most TCP connections the echo server will serve at once
+    pub echo_max_connections: usize,
+    /// How long a TCP connection to the echo server can be idle before it is closed
+    pub echo_idle_timeout: Option<Duration>,
+    /// How many bytes the TCP echo server reads from a connection at once
+    pub echo_tcp_chunk_size: usize,
+
+    /// The raw value of every setting and where it came from, in the order of [SETTINGS]
+    resolved: Vec<(&'static str, String, Source)>,
+}
+
+impl Config {
+    /// Resolves the configuration. [config_file] is the file specified on the command line, if
+    /// any, and [flags] maps setting keys to values given on the command line.
+    pub fn load(config_file: Option<&str>, flags: &BTreeMap<String, String>) -> Result<Config, ConfigError> {
+        let mut values: BTreeMap<&'static str, (String, Source)> = SETTINGS.iter()
+            .map(|s| (s.key, (s.default.to_string(), Source::Default)))
+            .collect();
+
+        let config_file = config_file.map(str::to_string)
+            .or_else(|| env::var(CONFIG_FILE_ENV).ok());
+        let file_values = match config_file {
+            Some(ref path) => read_config_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_config_file(DEFAULT_CONFIG_FILE)?,
+            None => vec![],
+        };
+        for (key, value, source) in file_values {
+            values.insert(key, (value, source));
+        }
+
+        for setting in SETTINGS {
+            let var = setting.env_var();
+            if let Ok(value) = env::var(&var) {
+                values.insert(setting.key, (value, Source::Env(var)));
+            }
+            if let Some(value) = flags.get(setting.key) {
+                values.insert(setting.key, (value.clone(), Source::Flag(setting.flag.to_string())));
+            }
+        }
+
+        let invalid = |key: &'static str, reason: &str| {
+            let (ref value, ref source) = values[key];
+            ConfigError::Invalid { key, value: value.clone(), source: source.clone(), reason: reason.to_string() }
+        };
+        let address = |key: &'static str| -> Result<SocketAddr, ConfigError> {
+            create_address(&values[key].0).map_err(|_| invalid(key, "not a valid socket address"))
+        };
+        let number = |key: &'static str| -> Result<u64, ConfigError> {
+            values[key].0.parse::<u64>().map_err(|_| invalid(key, "not a valid non-negative integer"))
+        };
+
+        let networks = |key: &'static str| -> Result<Vec<Network>, ConfigError> {
+            values[key].0.split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.parse::<Network>().map_err(|e| invalid(key, &e)))
+                .collect()
+        };
+
+        let timeout_ms = number("timeout_ms")?;
+        if timeout_ms == 0 {
+            return Err(invalid("timeout_ms", "must be greater than zero"))
+        }
+        let echo_max_connections = number("echo_max_connections")?;
+        if echo_max_connections == 0 {
+            return Err(invalid("echo_max_connections", "must be greater than zero"))
+        }
+        let echo_idle_timeout_ms = number("echo_idle_timeout_ms")?;
+        let echo_tcp_chunk_size = number("echo_tcp_chunk_size")?;
+        if echo_tcp_chunk_size == 0 || echo_tcp_chunk_size > MAX_TCP_CHUNK_SIZE {
+            return Err(invalid("echo_tcp_chunk_size", &format!("must be between 1 and {}", MAX_TCP_CHUNK_SIZE)))
+        }
+
+        Ok(Config {
+            udp_ip: address("udp_ip")?,
+            echo_server_udp_ip: address("echo_server_udp_ip")?,
+            echo_server_tcp_ip: address("echo_server_tcp_ip")?,
+            timeout: Duration::from_millis(timeout_ms),
+            echo_allowed_clients: networks("echo_allowed_clients")?,
+            echo_max_connections: echo_max_connections as usize,
+            echo_idle_timeout: if echo_idle_timeout_ms == 0 { None } else { Some(Duration::from_millis(echo_idle_timeout_ms)) },
+            echo_tcp_chunk_size: echo_tcp_chunk_size as usize,
+            resolved: SETTINGS.iter()
+                .map(|s| { let (ref v, ref src) = values[s.key]; (s.key, v.clone(), src.clone()) })
+                .collect(),
+        })
+    }
+
+    /// Prints the effective value of every setting, and where it came from.
+    pub fn show(&self) {
+        let effective: BTreeMap<&str, String> = vec![
+            ("udp_ip", self.udp_ip.to_string()),
+            ("echo_server_udp_ip", self.echo_server_udp_ip.to_string()),
+            ("echo_server_tcp_ip", self.echo_server_tcp_ip.to_string()),
+            ("timeout_ms", self.timeout.as_millis().to_string()),
+            ("echo_allowed_clients", self.echo_allowed_clients.iter()
+                .map(Network::to_string).collect::<Vec<_>>().join(",")),
+            ("echo_max_connections", self.echo_max_connections.to_string()),
+            ("echo_idle_timeout_ms", self.echo_idle_timeout.map_or(0, |t| t.as_millis()).to_string()),
+            ("echo_tcp_chunk_size", self.echo_tcp_chunk_size.to_string()),
+        ].into_iter().collect();
+
+        for &(key, ref raw, ref source) in self.resolved.iter() {
+            let value = &effective[key];
+            if value == raw {
+                pretty_print("CFG", key, &format!("{} ({})", value, source), false);
+            } else {
+                pretty_print("CFG", key, &format!("{} (resolved from '{}', {})", value, raw, source), false);
+            }
+        }
+    }
+}
+
+/// Reads the settings out of a TOML config file. Every value must be a string, a number or an
+/// array of strings (which is treated as a comma separated list), and every key must be a known
+/// setting.
+fn read_config_file(path: &str) -> Result<Vec<(&'static str, String, Source)>, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| ConfigError::Io(path.to_string(), e))?;
+
+    let table: toml::value::Table = toml::from_str(&contents)
+        .map_err(|e| ConfigError::Parse(path.to_string(), e.to_string()))?;
+
+    let mut values = Vec::with_capacity(table.len());
+    for (key, value) in table {
+        let setting = match SETTINGS.iter().find(|s| s.key == key) {
+            Some(s) => s,
+            None => return Err(ConfigError::UnknownKey(path.to_string(), key)),
+        };
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Array(ref items) if items.iter().all(toml::Value::is_str) =>
+                items.iter().filter_map(toml::Value::as_str).collect::<Vec<_>>().join(","),
+            other => return Err(ConfigError::Invalid {
+                key: setting.key,
+                value: other.to_string(),
+                source: Source::File(path.to_string()),
+                reason: "expected a string, an integer or an array of strings".to_string(),
+            }),
+        };
+        values.push((setting.key, value, Source::File(path.to_string())));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    /// Writes [contents] to a config file only the calling test uses.
+    fn config_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("dl1-config-{}-{}.toml", process::id(), name)).to_string_lossy().into_owned();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn source<'a>(config: &'a Config, key: &str) -> &'a Source {
+        &config.resolved.iter().find(|&&(k, _, _)| k == key).unwrap().2
+    }
+
+    // This is the only test that sets environment variables, since they're shared by every test
+    #[test]
+    fn layers_defaults_file_env_and_flags() {
+        let path = config_file("layers", "timeout_ms = 2000\necho_max_connections = 10\necho_idle_timeout_ms = 500\n");
+        env::set_var("DL1_TIMEOUT_MS", "3000");
+        env::set_var("DL1_ECHO_MAX_CONNECTIONS", "20");
+        let flags: BTreeMap<String, String> = vec![("echo_max_connections".to_string(), "30".to_string())].into_iter().collect();
+        let config = Config::load(Some(&path), &flags);
+        env::remove_var("DL1_TIMEOUT_MS");
+        env::remove_var("DL1_ECHO_MAX_CONNECTIONS");
+        let _ = fs::remove_file(&path);
+        let config = config.unwrap();
+
+        assert_eq!(config.echo_tcp_chunk_size, 65536);
+        assert_eq!(*source(&config, "echo_tcp_chunk_size"), Source::Default);
+        assert_eq!(config.echo_idle_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(*source(&config, "echo_idle_timeout_ms"), Source::File(path.clone()));
+        assert_eq!(config.timeout, Duration::from_millis(3000));
+        assert_eq!(*source(&config, "timeout_ms"), Source::Env("DL1_TIMEOUT_MS".to_string()));
+        assert_eq!(config.echo_max_connections, 30);
+        assert_eq!(*source(&config, "echo_max_connections"), Source::Flag("echo-max-connections".to_string()));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_the_config_file() {
+        let path = config_file("unknown", "timeout_ms = 2000\ntimeout = 5\n");
+        let config = Config::load(Some(&path), &BTreeMap::new());
+        let _ = fs::remove_file(&path);
+        match config {
+            Err(ConfigError::UnknownKey(_, key)) => assert_eq!(key, "timeout"),
+            result => panic!("expected the unknown key to be rejected, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reads_arrays_of_networks_from_the_config_file() {
+        let path = config_file("networks", "echo_allowed_clients = [\"129.3.0.0/16\", \"127.0.0.1\"]\n");
+        let config = Config::load(Some(&path), &BTreeMap::new());
+        let _ = fs::remove_file(&path);
+        let networks: Vec<String> = config.unwrap().echo_allowed_clients.iter().map(Network::to_string).collect();
+        assert_eq!(networks, vec!["129.3.0.0/16", "127.0.0.1/32"]);
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::io::{ Read, Write, self };
+use std::net::{ Shutdown, SocketAddr, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime };
+
+use serde_json::{ self, Value };
+
+use error::TestError;
+use test::{ Direction, Protocol, Test, TestSpec };
+use util::pretty_print;
+
+/// The version of the control protocol. A client and an echo server only run tests together if
+/// they speak the same version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A client opens a control connection by sending this, followed by a [ControlMessage::Hello].
+pub const CONTROL_MAGIC: &[u8] = b"DL1 CONTROL\n";
+/// A client opens a data connection for a test by sending this, followed by the test's token and
+/// a newline. Everything after that is the test's data.
+pub const DATA_MAGIC: &[u8] = b"DL1 DATA ";
+/// The longest the start of a data connection can be, newline included.
+pub const MAX_DATA_GREETING_LEN: usize = 64;
+/// The longest a control message can be.
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// How much of a stream is read at once.
+pub const READ_LEN: usize = 64 * 1024;
+
+/// Bulk TCP tests stream a repeating pattern of this length, so what arrives can be checked. The
+/// length is prime so the pattern doesn't line up with the chunks it's written and read in.
+pub const BULK_PATTERN_LEN: usize = 251;
+
+/// The pattern a bulk TCP test streams, long enough that any chunk of up to [len] bytes can be
+/// taken from it starting at any offset into the pattern.
+pub fn bulk_pattern(len: usize) -> Vec<u8> {
+    (0..len + BULK_PATTERN_LEN).map(|i| (i % BULK_PATTERN_LEN) as u8).collect()
+}
+
+/// A message sent over a control connection. Each one is sent as its length (4 bytes, big
+/// endian) followed by that many bytes of JSON.
+///
+/// The client says hello, then starts and finishes each test of its plan in turn, and says
+/// goodbye. The echo server answers every message, with [ControlMessage::Error] if it can't do
+/// what was asked.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// The version of the protocol the client speaks, and every test it's going to run
+    Hello { version: u32, plan: Vec<Test> },
+    /// The echo server accepted the hello
+    Welcome { version: u32 },
+    Error { message: String },
+    /// The client is about to run test [index] of the plan
+    StartTest { index: usize },
+    /// The echo server is ready for a test. Its data connections start with [token], and its
+    /// UDP probes carry [test_id].
+    Ready { token: u64, test_id: u32 },
+    /// Test [index] of the plan is over
+    FinishTest { index: usize },
+    /// What the echo server saw of a test
+    TestStats { stats: ServerStats },
+    /// The client is done with every test
+    Goodbye,
+    /// What the echo server saw over the whole session
+    Farewell { stats: ServerStats },
+}
+
+/// What the echo server saw of a test (or every test of a session).
+#[derive(Serialize, Deserialize, Debug, Hash, Clone, Default, PartialEq)]
+pub struct ServerStats {
+    /// How many data connections were opened
+    pub tcp_connections: u64,
+    pub tcp_bytes_received: u64,
+    pub tcp_bytes_sent: u64,
+    pub datagrams_received: u64,
+    pub datagrams_echoed: u64,
+    pub udp_bytes_echoed: u64,
+}
+
+impl ServerStats {
+    fn add(&mut self, other: &ServerStats) {
+        self.tcp_connections += other.tcp_connections;
+        self.tcp_bytes_received += other.tcp_bytes_received;
+        self.tcp_bytes_sent += other.tcp_bytes_sent;
+        self.datagrams_received += other.datagrams_received;
+        self.datagrams_echoed += other.datagrams_echoed;
+        self.udp_bytes_echoed += other.udp_bytes_echoed;
+    }
+}
+
+/// Counts what the echo server sees of a test while it runs, from whichever thread serves it.
+#[derive(Debug, Default)]
+pub struct TestCounters {
+    pub tcp_connections: AtomicU64,
+    pub tcp_bytes_received: AtomicU64,
+    pub tcp_bytes_sent: AtomicU64,
+    pub datagrams_received: AtomicU64,
+    pub datagrams_echoed: AtomicU64,
+    pub udp_bytes_echoed: AtomicU64,
+}
+
+impl TestCounters {
+    fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            tcp_connections: self.tcp_connections.load(Ordering::SeqCst),
+            tcp_bytes_received: self.tcp_bytes_received.load(Ordering::SeqCst),
+            tcp_bytes_sent: self.tcp_bytes_sent.load(Ordering::SeqCst),
+            datagrams_received: self.datagrams_received.load(Ordering::SeqCst),
+            datagrams_echoed: self.datagrams_echoed.load(Ordering::SeqCst),
+            udp_bytes_echoed: self.udp_bytes_echoed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// What the echo server set aside for a test, which the client needs to run it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Allocation {
+    pub token: u64,
+    pub test_id: u32,
+}
+
+impl Allocation {
+    /// What a data connection for the test starts with.
+    pub fn data_greeting(&self) -> Vec<u8> {
+        let mut greeting = DATA_MAGIC.to_vec();
+        greeting.extend(format!("{}\n", self.token).bytes());
+        greeting
+    }
+}
+
+/// A test that one of the echo server's control sessions has started.
+#[derive(Debug, Clone)]
+pub struct Allocated {
+    pub test: Test,
+    pub counters: Arc<TestCounters>,
+}
+
+/// Every test the echo server's control sessions have started and not yet finished, so the data
+/// connections and datagrams of each test can be counted towards it.
+#[derive(Debug, Default)]
+pub struct Allocations {
+    by_token: Mutex<HashMap<u64, Allocated>>,
+    by_test_id: Mutex<HashMap<u32, Arc<TestCounters>>>,
+}
+
+impl Allocations {
+    fn allocate(&self, test: Test) -> (Allocation, Arc<TestCounters>) {
+        let counters = Arc::new(TestCounters::default());
+        let mut by_token = self.by_token.lock().unwrap();
+        let mut by_test_id = self.by_test_id.lock().unwrap();
+
+        let mut s = DefaultHasher::new();
+        SystemTime::now().hash(&mut s);
+        by_token.len().hash(&mut s);
+        let mut token = s.finish();
+        while by_token.contains_key(&token) {
+            token = token.wrapping_add(1);
+        }
+        let mut test_id = token as u32;
+        while by_test_id.contains_key(&test_id) {
+            test_id = test_id.wrapping_add(1);
+        }
+
+        by_token.insert(token, Allocated { test, counters: counters.clone() });
+        by_test_id.insert(test_id, counters.clone());
+        (Allocation { token, test_id }, counters)
+    }
+
+    fn release(&self, allocation: &Allocation) {
+        self.by_token.lock().unwrap().remove(&allocation.token);
+        self.by_test_id.lock().unwrap().remove(&allocation.test_id);
+    }
+
+    /// The test a data connection that started with [token] is for.
+    pub fn data_connection(&self, token: u64) -> Option<Allocated> {
+        self.by_token.lock().unwrap().get(&token).cloned()
+    }
+
+    /// The counters of the test that sends UDP probes with [test_id].
+    pub fn datagram_counters(&self, test_id: u32) -> Option<Arc<TestCounters>> {
+        self.by_test_id.lock().unwrap().get(&test_id).cloned()
+    }
+}
+
+pub fn write_message<W: Write>(stream: &mut W, message: &ControlMessage) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+    frame.extend(json);
+    stream.write_all(&frame)
+}
+
+/// Reads the JSON of the next control message, whose length has already been read into [len].
+fn read_frame<R: Read>(stream: &mut R, len: [u8; 4]) -> io::Result<Vec<u8>> {
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("a control message of {} bytes is longer than the limit of {} bytes", len, MAX_MESSAGE_LEN)))
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+pub fn read_message<R: Read>(stream: &mut R) -> io::Result<ControlMessage> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    Ok(serde_json::from_slice(&read_frame(stream, len)?)?)
+}
+
+/// The version a hello or welcome was sent with, which is checked before anything else about
+/// the message, since the rest of it might be laid out differently in other versions.
+fn message_version(frame: &[u8]) -> Option<u64> {
+    serde_json::from_slice::<Value>(frame).ok()?.get("version")?.as_u64()
+}
+
+fn version_mismatch(server_version: u64, client_version: u64) -> String {
+    format!("the echo server speaks version {} of the control protocol, but the client speaks version {}", server_version, client_version)
+}
+
+/// The client's end of a control connection.
+pub struct ControlChannel {
+    stream: TcpStream,
+}
+
+impl ControlChannel {
+    /// Opens a control connection to the echo server at [addr], and announces [plan].
+    pub fn open(addr: SocketAddr, plan: &[Test], timeout: Duration) -> Result<ControlChannel, TestError> {
+        pretty_print("LOG", "Handshake", "Beginning handshake.", false);
+        let handshake_error = |e: io::Error| TestError::Handshake(format!("failed to reach the echo server, encountered error '{}'", e));
+        let mut stream = TcpStream::connect(addr).map_err(handshake_error)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(CONTROL_MAGIC).map_err(handshake_error)?;
+        write_message(&mut stream, &ControlMessage::Hello { version: PROTOCOL_VERSION, plan: plan.to_vec() }).map_err(handshake_error)?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)
+            .map_err(|e| TestError::Handshake(format!("no response from the echo server ({})", TestError::from(e))))?;
+        // An echo server that doesn't know about the control protocol just echoes it back
+        if len[..] == CONTROL_MAGIC[..4] {
+            return Err(TestError::Handshake("the echo server echoed the hello back, so it doesn't speak the control protocol".to_string()))
+        }
+        let frame = read_frame(&mut stream, len)
+            .map_err(|e| TestError::Handshake(format!("failed to read the echo server's response, encountered error '{}'", e)))?;
+        if let Some(version) = message_version(&frame) {
+            if version != PROTOCOL_VERSION as u64 {
+                return Err(TestError::Handshake(version_mismatch(version, PROTOCOL_VERSION as u64)))
+            }
+        }
+
+        let result = match serde_json::from_slice(&frame) {
+            Ok(ControlMessage::Welcome { .. }) => Ok(ControlChannel { stream }),
+            Ok(ControlMessage::Error { message }) => Err(TestError::Handshake(message)),
+            Ok(message) => Err(TestError::Handshake(format!("the echo server responded with {:?}", message))),
+            Err(e) => Err(TestError::Handshake(format!("the echo server's response isn't a control message ({})", e))),
+        };
+        match result {
+            Ok(_) => pretty_print("LOG", "Handshake", &format!("Successfully completed handshake (control protocol version {}).", PROTOCOL_VERSION), false),
+            Err(ref e) => pretty_print("ERR", "Handshake", &format!("Failed to complete handshake with echo server: {}", e), false),
+        }
+        result
+    }
+
+    /// Sends [message] and waits for the echo server's answer.
+    fn request(&mut self, message: ControlMessage) -> Result<ControlMessage, TestError> {
+        write_message(&mut self.stream, &message)?;
+        match read_message(&mut self.stream) {
+            Ok(ControlMessage::Error { message }) => Err(TestError::Handshake(message)),
+            Ok(answer) => Ok(answer),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => Err(TestError::Handshake(format!("the echo server sent an invalid control message ({})", e))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Tells the echo server test [index] of the plan is about to run.
+    pub fn start_test(&mut self, index: usize) -> Result<Allocation, TestError> {
+        match self.request(ControlMessage::StartTest { index })? {
+            ControlMessage::Ready { token, test_id } => Ok(Allocation { token, test_id }),
+            answer => Err(TestError::Handshake(format!("expected the echo server to be ready for test {}, but it responded with {:?}", index, answer))),
+        }
+    }
+
+    /// Tells the echo server test [index] of the plan is over, and returns what it saw of it.
+    pub fn finish_test(&mut self, index: usize) -> Result<ServerStats, TestError> {
+        match self.request(ControlMessage::FinishTest { index })? {
+            ControlMessage::TestStats { stats } => Ok(stats),
+            answer => Err(TestError::Handshake(format!("expected the echo server's statistics for test {}, but it responded with {:?}", index, answer))),
+        }
+    }
+
+    /// Ends the session, returning what the echo server saw over all of it.
+    pub fn close(mut self) -> Result<ServerStats, TestError> {
+        match self.request(ControlMessage::Goodbye)? {
+            ControlMessage::Farewell { stats } => Ok(stats),
+            answer => Err(TestError::Handshake(format!("expected the echo server to say goodbye, but it responded with {:?}", answer))),
+        }
+    }
+}
+
+/// Serves a control connection the echo server accepted, after reading [CONTROL_MAGIC] and
+/// [received]. Every test the client starts is allocated in [allocations] until it's finished, or
+/// the connection is closed. Returns what the echo server saw over the whole session.
+///
+/// [timeout] only limits how long writing an answer can take. The control connection is silent
+/// while a test runs, however long it is, so waiting for the next request never times out.
+/// Instead TCP keepalive checks the client is still there, so the session is closed (and stops
+/// counting towards the echo server's connections) if the client goes away without closing it.
+pub fn serve_session(stream: TcpStream, received: &[u8], allocations: &Allocations, max_connections: usize,
+                     timeout: Option<Duration>) -> io::Result<ServerStats> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(timeout)?;
+    set_keepalive(&stream)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = received.chain(stream);
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let hello = read_frame(&mut reader, len)?;
+    let plan = match accept_hello(&hello, max_connections) {
+        Ok(plan) => plan,
+        Err(message) => {
+            write_message(&mut writer, &ControlMessage::Error { message: message.clone() })?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message))
+        },
+    };
+    write_message(&mut writer, &ControlMessage::Welcome { version: PROTOCOL_VERSION })?;
+
+    let mut started: HashMap<usize, (Allocation, Arc<TestCounters>)> = HashMap::new();
+    let mut totals = ServerStats::default();
+    let result = (|| -> io::Result<()> { loop {
+        let answer = match read_message(&mut reader)? {
+            ControlMessage::StartTest { index } => match plan.get(index) {
+                _ if started.contains_key(&index) => ControlMessage::Error { message: format!("test {} was already started", index) },
+                Some(test) => {
+                    let (allocation, counters) = allocations.allocate(test.clone());
+                    started.insert(index, (allocation, counters));
+                    ControlMessage::Ready { token: allocation.token, test_id: allocation.test_id }
+                },
+                None => ControlMessage::Error { message: format!("there is no test {}, the plan only has {} tests", index, plan.len()) },
+            },
+            ControlMessage::FinishTest { index } => match started.remove(&index) {
+                Some((allocation, counters)) => {
+                    allocations.release(&allocation);
+                    let stats = counters.snapshot();
+                    totals.add(&stats);
+                    ControlMessage::TestStats { stats }
+                },
+                None => ControlMessage::Error { message: format!("test {} was never started", index) },
+            },
+            ControlMessage::Goodbye => {
+                write_message(&mut writer, &ControlMessage::Farewell { stats: totals.clone() })?;
+                return Ok(())
+            },
+            message => ControlMessage::Error { message: format!("unexpected control message {:?}", message) },
+        };
+        write_message(&mut writer, &answer)?;
+    } })();
+
+    for (allocation, counters) in started.values() {
+        allocations.release(allocation);
+        totals.add(&counters.snapshot());
+    }
+    result.map(|()| totals)
+}
+
+/// Turns on TCP keepalive for [stream], so reading from it fails once the other end has been
+/// unreachable for a couple of minutes.
+#[cfg(unix)]
+fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    let set = |level: libc::c_int, option: libc::c_int, value: libc::c_int| {
+        let result = unsafe {
+            libc::setsockopt(stream.as_raw_fd(), level, option, &value as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    };
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    // Start checking after a minute of silence, then every 10 seconds, and give up after 6
+    // checks go unanswered. Elsewhere the system wide settings are used, which usually wait hours
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, 60)?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, 10)?;
+        set(libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 6)?;
+    }
+    Ok(())
+}
+
+/// Turns on TCP keepalive for [stream], which isn't supported on this platform.
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream) -> io::Result<()> {
+    Ok(())
+}
+
+/// Checks the version of a hello, and that the echo server can run every test of its plan.
+fn accept_hello(hello: &[u8], max_connections: usize) -> Result<Vec<Test>, String> {
+    match message_version(hello) {
+        Some(version) if version == PROTOCOL_VERSION as u64 => {},
+        Some(version) => return Err(version_mismatch(PROTOCOL_VERSION as u64, version)),
+        None => return Err("the first control message has to be a hello with a version".to_string()),
+    }
+    let plan = match serde_json::from_slice(hello) {
+        Ok(ControlMessage::Hello { plan, .. }) => plan,
+        Ok(message) => return Err(format!("expected a hello, got {:?}", message)),
+        Err(e) => return Err(format!("invalid hello ({})", e)),
+    };
+
+    for (i, test) in plan.iter().enumerate() {
+        test.validate().map_err(|e| format!("test {} ({}) is not valid: {}", i, test, e))?;
+        let connections = test.spec().parallel.unwrap_or(1) as usize;
+        if test.protocol() == Protocol::Tcp && connections >= max_connections {
+            return Err(format!("test {} ({}) needs {} connections, but the echo server serves at most {} at once", i, test, connections, max_connections))
+        }
+    }
+    Ok(plan)
+}
+
+/// Writes [pattern] to [stream] in chunks of [chunk_len] bytes until [duration] has passed since
+/// [start] or [total_bytes] have been written. [bytes_written] is called with the running total
+/// after every write.
+pub fn write_pattern<F: FnMut(u64)>(stream: &mut TcpStream, pattern: &[u8], chunk_len: usize, duration: Option<Duration>,
+                                    total_bytes: Option<u64>, start: Instant, mut bytes_written: F) -> io::Result<u64> {
+    let total_bytes = total_bytes.unwrap_or(u64::MAX);
+    let mut sent = 0u64;
+    while sent < total_bytes && duration.is_none_or(|duration| start.elapsed() < duration) {
+        let offset = (sent % BULK_PATTERN_LEN as u64) as usize;
+        let len = (chunk_len as u64).min(total_bytes - sent) as usize;
+        match stream.write(&pattern[offset..offset + len]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "the connection stopped accepting data")),
+            Ok(written) => {
+                sent += written as u64;
+                bytes_written(sent);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+/// Serves a data connection of a reverse or bidirectional test following [spec], after reading
+/// the data greeting and [received].
+///
+/// The pattern is streamed to the client until the test's duration passes or byte count is sent.
+/// For bidirectional tests everything the client streams is read and checked at the same time,
+/// and the connection is only closed once the client is done sending, so the client knows all of
+/// it arrived. Returns how many bytes were sent and received, which are also added to [counters].
+pub fn serve_stream(mut stream: TcpStream, spec: &TestSpec, received: &[u8], counters: &TestCounters,
+                    timeout: Option<Duration>) -> io::Result<(u64, u64)> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    let pattern = bulk_pattern(spec.message_len.max(READ_LEN));
+    let mut reader = stream.try_clone()?;
+    let start = Instant::now();
+    thread::scope(|scope| {
+        let read = match spec.direction {
+            Direction::Bidirectional => Some(scope.spawn(|| read_pattern(&mut reader, &pattern, received, counters))),
+            _ => None,
+        };
+        let mut counted = 0;
+        let sent = write_pattern(&mut stream, &pattern, spec.message_len, spec.duration_ms.map(Duration::from_millis), spec.total_bytes, start, |sent| {
+            counters.tcp_bytes_sent.fetch_add(sent - counted, Ordering::Relaxed);
+            counted = sent;
+        });
+        let received = match read {
+            Some(handle) => handle.join().unwrap_or_else(|_| Err(io::Error::other("the thread reading the stream panicked")))?,
+            None => 0,
+        };
+        let sent = sent?;
+        stream.shutdown(Shutdown::Write)?;
+        Ok((sent, received))
+    })
+}
+
+/// Reads from [stream] until the client stops sending, checking that everything matches
+/// [pattern]. [received] is the start of the stream, which was read along with the greeting.
+fn read_pattern(stream: &mut TcpStream, pattern: &[u8], received: &[u8], counters: &TestCounters) -> io::Result<u64> {
+    let mismatch = |at: u64| io::Error::new(io::ErrorKind::InvalidData, format!("the stream differs from the pattern at byte {}", at));
+    if let Some(i) = (0..received.len()).find(|&i| received[i] != (i % BULK_PATTERN_LEN) as u8) {
+        return Err(mismatch(i as u64))
+    }
+
+    let mut buffer = vec![0u8; READ_LEN];
+    let mut total = received.len() as u64;
+    counters.tcp_bytes_received.fetch_add(total, Ordering::Relaxed);
+    loop {
+        let bytes_read = match stream.read(&mut buffer) {
+            Ok(0) => return Ok(total),
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let offset = (total % BULK_PATTERN_LEN as u64) as usize;
+        if let Some(i) = (0..bytes_read).find(|&i| buffer[i] != pattern[offset + i]) {
+            return Err(mismatch(total + i as u64))
+        }
+        total += bytes_read as u64;
+        counters.tcp_bytes_received.fetch_add(bytes_read as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn hello(version: u32, plan: Vec<Test>) -> Vec<u8> {
+        serde_json::to_vec(&ControlMessage::Hello { version, plan }).unwrap()
+    }
+
+    fn tcp_test(parallel: Option<u32>) -> Test {
+        Test::TcpTest(TestSpec { num_messages: 4, message_len: 64, parallel, ..TestSpec::default() })
+    }
+
+    #[test]
+    fn accepts_a_hello_the_echo_server_can_run() {
+        assert_eq!(accept_hello(&hello(PROTOCOL_VERSION, vec![tcp_test(Some(2))]), 8).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_a_hello_of_another_version() {
+        let error = accept_hello(&hello(PROTOCOL_VERSION + 1, vec![]), 8).unwrap_err();
+        assert_eq!(error, version_mismatch(PROTOCOL_VERSION as u64, PROTOCOL_VERSION as u64 + 1));
+        assert!(accept_hello(br#"{"type":"goodbye"}"#, 8).is_err());
+    }
+
+    #[test]
+    fn rejects_a_plan_with_an_invalid_test() {
+        let invalid = Test::TcpTest(TestSpec { num_messages: 0, message_len: 64, ..TestSpec::default() });
+        let error = accept_hello(&hello(PROTOCOL_VERSION, vec![tcp_test(None), invalid]), 8).unwrap_err();
+        assert!(error.starts_with("test 1 "), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_plan_that_needs_too_many_connections() {
+        // The control connection takes up one of them
+        let error = accept_hello(&hello(PROTOCOL_VERSION, vec![tcp_test(Some(8))]), 8).unwrap_err();
+        assert!(error.contains("needs 8 connections"), "{}", error);
+        assert!(accept_hello(&hello(PROTOCOL_VERSION, vec![tcp_test(Some(7))]), 8).is_ok());
+    }
+
+    #[test]
+    fn serves_a_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let allocations = Allocations::default();
+        let plan = vec![tcp_test(None), Test::UdpTest(TestSpec { num_messages: 4, message_len: 64, ..TestSpec::default() })];
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut magic = [0u8; CONTROL_MAGIC.len()];
+                stream.read_exact(&mut magic).unwrap();
+                assert_eq!(&magic[..], CONTROL_MAGIC);
+                serve_session(stream, &[], &allocations, 8, Some(Duration::from_secs(5)))
+            });
+
+            let mut channel = ControlChannel::open(addr, &plan, Duration::from_secs(5)).unwrap();
+            let allocation = channel.start_test(1).unwrap();
+            assert!(allocations.data_connection(allocation.token).is_some());
+            let counters = allocations.datagram_counters(allocation.test_id).unwrap();
+            counters.datagrams_received.fetch_add(4, Ordering::SeqCst);
+            counters.datagrams_echoed.fetch_add(3, Ordering::SeqCst);
+
+            let stats = channel.finish_test(1).unwrap();
+            assert_eq!(stats, ServerStats { datagrams_received: 4, datagrams_echoed: 3, ..ServerStats::default() });
+            assert!(allocations.data_connection(allocation.token).is_none());
+            assert!(channel.finish_test(1).is_err());
+            assert!(channel.start_test(2).is_err());
+
+            assert_eq!(channel.close().unwrap(), stats);
+            assert_eq!(server.join().unwrap().unwrap(), stats);
+        });
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::{ self, File };
+use std::io::{ Write, self };
+use std::path::{ Path, PathBuf };
+use std::process;
+
+/// A file containing the id of this process, which is removed when this is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<PidFile, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detaches the process from the terminal and keeps running it in the background: the process
+/// forks (the parent exits), becomes the leader of a new session, and has its standard input
+/// replaced with /dev/null and its output redirected to [log_file] (or /dev/null).
+///
+/// This has to be called before any threads are started, since only the calling thread survives
+/// the fork.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), io::Error> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    // Open these before forking, so errors can still be reported to the terminal
+    let null = File::open("/dev/null")?;
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {},
+        child => {
+            println!("Started echo server in the background with pid {}.", child);
+            process::exit(0)
+        },
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error())
+    }
+
+    io::stdout().flush()?;
+    for &(file, fd) in &[(&null, libc::STDIN_FILENO), (&output, libc::STDOUT_FILENO), (&output, libc::STDERR_FILENO)] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> Result<(), io::Error> {
+    Err(io::Error::other("running as a daemon is only supported on unix"))
+}
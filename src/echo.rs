@@ -1,144 +1,794 @@
 use std::cmp::min;
-use std::net::*;
-use std::sync::mpsc::{ Receiver, channel };
-use std::thread;
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ Receiver, Sender, channel };
+use std::thread::{ self, JoinHandle };
 use std::io::{ Write, Read, BufRead, self };
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 
+use mio::{ Events, Interest, Poll, Registry, Token, Waker };
+use mio::net::{ TcpListener, TcpStream, UdpSocket };
+use signal_hook::consts::{ SIGINT, SIGTERM };
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::signal_name;
 
 use util::pretty_print;
-use config::*;
+use config::Config;
+use control::{ self, Allocated, Allocations, TestCounters, CONTROL_MAGIC, DATA_MAGIC, MAX_DATA_GREETING_LEN };
+use probe::ProbeHeader;
+use test::Direction;
+use daemon::{ self, PidFile };
+
+/// Wakes an event loop up when the kill signal is sent to it.
+const WAKER: Token = Token(0);
+/// The TcpListener or UdpSocket an event loop is serving.
+const SOCKET: Token = Token(1);
+/// The token of the first TCP connection; every connection after it gets the next token.
+const FIRST_CONNECTION: Token = Token(2);
+
+/// How many events are handled per call to poll.
+const EVENT_CAPACITY: usize = 1024;
+
+/// How the echo server is ran and told to stop.
+#[derive(Debug, Default)]
+pub struct EchoOptions {
+    /// Stop when a line is read from standard input. The server always stops on SIGINT or SIGTERM.
+    pub read_stdin: bool,
+    /// Detach from the terminal and run in the background.
+    pub daemon: bool,
+    /// Where to write the id of the echo server's process while it runs.
+    pub pid_file: Option<PathBuf>,
+    /// Where output is written to when running as a daemon.
+    pub log_file: Option<PathBuf>,
+}
+
+/// Totals over the whole time the echo server ran, printed when it stops.
+#[derive(Debug, Default)]
+pub struct EchoSummary {
+    pub connections_served: u64,
+    pub tcp_bytes_echoed: u64,
+    /// Writes to TCP connections that couldn't send everything they were given, because the
+    /// connection's send buffer was full
+    pub short_writes: u64,
+    pub datagrams_echoed: u64,
+    pub udp_bytes_echoed: u64,
+    /// Control connections that tests were ran over
+    pub sessions_served: u64,
+    /// Data connections of reverse and bidirectional tests, which were streamed to instead of echoed
+    pub streams_served: u64,
+}
+
+pub fn start_echo_server(config: &Config, options: &EchoOptions) -> Result<(), io::Error> {
+    // This has to happen before any threads are spawned
+    if options.daemon {
+        daemon::daemonize(options.log_file.as_deref())?;
+    }
+    let _pid_file = match options.pid_file {
+        Some(ref path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+
+    // Anything that should stop the echo server sends the reason over this channel
+    let (stop_send, stop_recv) = channel();
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let signals_handle = signals.handle();
+    let signal_stop = stop_send.clone();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            let name = signal_name(signal).unwrap_or("a signal");
+            let _ = signal_stop.send(format!("Received {}", name));
+        }
+    });
+
+    if options.read_stdin {
+        let stdin_stop = stop_send.clone();
+        thread::spawn(move || {
+            let mut s = String::new();
+            let _ = io::stdin().lock().read_line(&mut s);
+            let _ = stdin_stop.send("Read a line from standard input".to_string());
+        });
+    }
 
-pub fn start_echo_server() -> Result<(), io::Error> {
     let (tcp_send, tcp_recv) = channel();
     let (udp_send, udp_recv) = channel();
 
-    let tcp_handle = thread::spawn(move || { tcp_echo(tcp_recv) });
-    let udp_handle = thread::spawn(move || { udp_echo(udp_recv) });
+    // Each event loop gets a waker so it can be woken up to notice the kill signal
+    let tcp_poll = Poll::new()?;
+    let tcp_waker = Waker::new(tcp_poll.registry(), WAKER)?;
+    let udp_poll = Poll::new()?;
+    let udp_waker = Waker::new(udp_poll.registry(), WAKER)?;
+
+    // What control sessions have set aside for their tests, which both event loops count towards
+    let allocations = Arc::new(Allocations::default());
+    let tcp_allocations = allocations.clone();
+    let tcp_config = config.clone();
+    let udp_config = config.clone();
+    // If either event loop stops on its own there's no point in keeping the other one running
+    let tcp_stop = stop_send.clone();
+    let udp_stop = stop_send.clone();
+    let tcp_handle = thread::spawn(move || {
+        let result = tcp_echo(tcp_config, tcp_poll, tcp_recv, tcp_allocations);
+        let _ = tcp_stop.send("The TCP echo server stopped".to_string());
+        result
+    });
+    let udp_handle = thread::spawn(move || {
+        let result = udp_echo(udp_config, udp_poll, udp_recv, allocations);
+        let _ = udp_stop.send("The UDP echo server stopped".to_string());
+        result
+    });
+
+    if options.read_stdin {
+        println!("Successfully started echo server. Press enter or send SIGINT/SIGTERM to close the echo server.");
+    } else {
+        pretty_print("LOG", "Echo Server", &format!("Successfully started echo server (pid {}). Send SIGINT/SIGTERM to close the echo server.",
+                                                   process::id()), false);
+    }
+
+    // Wait until something tells the server to stop, then send the kill signal to both threads
+    // and wait for them to return
+    if let Ok(reason) = stop_recv.recv() {
+        pretty_print("LOG", "Echo Server", &format!("{}, closing the echo server.", reason), false);
+    }
+    signals_handle.close();
+
+    let tcp_summary = stop_event_loop("TCP", &tcp_send, &tcp_waker, tcp_handle);
+    let udp_summary = stop_event_loop("UDP", &udp_send, &udp_waker, udp_handle);
+
+    if let Some(peak) = peak_resident_memory() {
+        pretty_print("LOG", "Echo Server", &format!("Peak resident memory {}", peak), false);
+    }
+
+    let summary = EchoSummary {
+        datagrams_echoed: udp_summary.datagrams_echoed,
+        udp_bytes_echoed: udp_summary.udp_bytes_echoed,
+        ..tcp_summary
+    };
+    pretty_print("LOG", "Summary", &format!("Served {} TCP connections ({} control sessions, {} streams) and echoed {} bytes over TCP ({} short writes), echoed {} datagrams ({} bytes) over UDP",
+                                            summary.connections_served, summary.sessions_served, summary.streams_served, summary.tcp_bytes_echoed, summary.short_writes,
+                                            summary.datagrams_echoed, summary.udp_bytes_echoed), false);
+
+    Ok(())
+}
 
-    let stdin = io::stdin();
+/// Sends the kill signal to one of the event loops and waits for it to return. Returns what the
+/// event loop did, or an empty summary if it failed.
+fn stop_event_loop(name: &str, kill_send: &Sender<()>, waker: &Waker, handle: JoinHandle<Result<EchoSummary, io::Error>>) -> EchoSummary {
+    let key = format!("{} Thread", name);
 
-    let mut s = String::new();
+    // If the thread already returned there is nothing to send the kill signal to
+    if kill_send.send(()).is_ok() {
+        let _ = waker.wake();
+    }
 
-    println!("Successfully started echo server. Press any key to close the echo server.");
+    match handle.join() {
+        Ok(Ok(summary)) => {
+            pretty_print("LOG", &key, &format!("Successfully closed {} thread.", name), false);
+            summary
+        },
+        Ok(Err(e)) => {
+            pretty_print("ERR", &key, &format!("{} thread encountered error '{:?}' while executing", name, e), false);
+            EchoSummary::default()
+        },
+        Err(e) => {
+            pretty_print("ERR", &key, &format!("Failed to join with {} thread, encountered error {:?}", name, e), false);
+            EchoSummary::default()
+        },
+    }
+}
 
-    // Wait unti enter is pressed, then send the kill signal to both threads and wait for them
-    // to returna
-    let mut handle = stdin.lock();
-    handle.read_line(&mut s)?;
+/// The largest datagram the UDP echo server can receive.
+const UDP_BUFFER_LEN: usize = 64 * 1024;
 
-    // If the tcp thread has closed
-    if let Err(e) = tcp_send.send(()) {
-        if let Ok(Err(e)) = tcp_handle.join() {
-            pretty_print("ERR", "TCP Thread", &format!("TCP thread encountered error '{:?}' while executing", e), false);
-        } else {
-            pretty_print("ERR", "TCP Thread", &format!("Failed send kill signal to TCP thread, encountered error '{:?}'", e), false);
+/// Hands out fixed size buffers and takes them back when they are no longer needed, so the
+/// buffers are reused across connections instead of allocating one per connection. Only
+/// connections that have data waiting to be echoed hold a buffer.
+struct BufferPool {
+    buffer_len: usize,
+    free: Vec<Vec<u8>>,
+    /// How many buffers have been allocated in total, which never goes down
+    allocated: usize,
+    in_use: usize,
+    peak_in_use: usize,
+}
+
+impl BufferPool {
+    fn new(buffer_len: usize) -> BufferPool {
+        BufferPool { buffer_len, free: vec![], allocated: 0, in_use: 0, peak_in_use: 0 }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        self.in_use += 1;
+        self.peak_in_use = self.peak_in_use.max(self.in_use);
+        self.free.pop().unwrap_or_else(|| {
+            self.allocated += 1;
+            vec![0u8; self.buffer_len]
+        })
+    }
+
+    fn give(&mut self, buffer: Vec<u8>) {
+        self.in_use -= 1;
+        self.free.push(buffer);
+    }
+
+    /// The most memory the pool has ever held, in bytes.
+    fn peak_memory(&self) -> usize {
+        self.allocated * self.buffer_len
+    }
+}
+
+/// The peak resident set size of the process, as reported by the kernel (only available on Linux).
+fn peak_resident_memory() -> Option<String> {
+    let mut status = String::new();
+    File::open("/proc/self/status").ok()?.read_to_string(&mut status).ok()?;
+    status.lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .map(|line| line["VmHWM:".len()..].trim().to_string())
+}
+
+/// What a connection turned out to be for, when it isn't just echoed.
+enum Handover {
+    /// A control connection, which a client runs tests over
+    Session,
+    /// A data connection of a reverse or bidirectional test
+    Stream(Allocated),
+}
+
+/// A TCP client of the echo server.
+struct Connection {
+    stream: TcpStream,
+    socket_addr: SocketAddr,
+    /// Holds bytes that have been read but not yet echoed (buffer[echoed..read]), because the
+    /// socket's send buffer was full. Nothing more is read from the connection until these have
+    /// been written. The buffer is only borrowed from the pool while there are such bytes.
+    buffer: Option<Vec<u8>>,
+    echoed: usize,
+    read: usize,
+    /// Whether the connection is registered for writable events, which is only needed while
+    /// there are pending bytes
+    waiting_to_write: bool,
+    last_activity: Instant,
+    bytes_echoed: u64,
+    /// How many writes sent fewer bytes than were pending
+    short_writes: u64,
+    /// The most bytes that were ever waiting to be echoed at once
+    peak_pending: usize,
+    /// Whether what has been read so far could still be the start of a control or data
+    /// connection, in which case it's kept in [held] instead of being echoed
+    greeting: bool,
+    /// What might be the start of a control or data connection. Once the connection turns out to
+    /// be one, this is whatever was read after the greeting.
+    held: Vec<u8>,
+    /// Bytes read while greeting that haven't been echoed yet, which are written before anything
+    /// in [buffer]
+    unsent: Vec<u8>,
+    /// What the connection has to be handed over to another thread for, once it's known
+    handover: Option<Handover>,
+    /// The test the connection is a data connection of, if it is one
+    counters: Option<Arc<TestCounters>>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, socket_addr: SocketAddr) -> Connection {
+        Connection {
+            stream,
+            socket_addr,
+            buffer: None,
+            echoed: 0,
+            read: 0,
+            waiting_to_write: false,
+            last_activity: Instant::now(),
+            bytes_echoed: 0,
+            short_writes: 0,
+            peak_pending: 0,
+            greeting: true,
+            held: vec![],
+            unsent: vec![],
+            handover: None,
+            counters: None,
         }
-    } else {
-        if let Err(e) = tcp_handle.join() {
-            pretty_print("ERR", "TCP Thread", &format!("Failed to join with TCP thread, encountered error {:?}", e), false);
-        } else {
-            pretty_print("LOG", "TCP Thread", "Successfully closed TCP thread.", false);
+    }
+
+    /// Echoes everything that can be read from the connection without blocking. Returns false
+    /// once the connection should be closed, or [handover] to another thread.
+    ///
+    /// Every byte that is read is echoed before anything more is read: when a write only sends
+    /// part of what is pending the rest is kept and written once the connection is writable again,
+    /// which also stops the client from sending more than the echo server can hold.
+    fn echo(&mut self, registry: &Registry, token: Token, pool: &mut BufferPool, allocations: &Allocations) -> bool {
+        self.last_activity = Instant::now();
+        loop {
+            // Finish echoing what was already read before reading anything new
+            while !self.unsent.is_empty() {
+                match write_counted(&mut self.stream, &self.unsent, self.counters.as_ref()) {
+                    Ok(0) => return false,
+                    Ok(bytes_written) => {
+                        if bytes_written < self.unsent.len() {
+                            self.short_writes += 1;
+                        }
+                        self.count_echoed(bytes_written);
+                        self.unsent.drain(..bytes_written);
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.short_writes += 1;
+                        return self.set_waiting_to_write(registry, token, true)
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => {
+                        pretty_print("ERR", "Echo Server", &format!("Failed to echo bytes back to {:?}, encountered error '{}'", self.socket_addr, e), false);
+                        return false
+                    },
+                }
+            }
+            if let Some(buffer) = self.buffer.take() {
+                while self.echoed < self.read {
+                    match write_counted(&mut self.stream, &buffer[self.echoed..self.read], self.counters.as_ref()) {
+                        Ok(0) => {
+                            pool.give(buffer);
+                            return false
+                        },
+                        Ok(bytes_written) => {
+                            if bytes_written < self.read - self.echoed {
+                                self.short_writes += 1;
+                            }
+                            self.count_echoed(bytes_written);
+                            self.echoed += bytes_written;
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            self.short_writes += 1;
+                            self.buffer = Some(buffer);
+                            return self.set_waiting_to_write(registry, token, true)
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                        Err(e) => {
+                            pretty_print("ERR", "Echo Server", &format!("Failed to echo bytes back to {:?}, encountered error '{}'", self.socket_addr, e), false);
+                            pool.give(buffer);
+                            return false
+                        },
+                    }
+                }
+                pool.give(buffer);
+            }
+            if !self.set_waiting_to_write(registry, token, false) {
+                return false
+            }
+
+            let mut buffer = pool.take();
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    pretty_print("LOG", "Echo Server", &format!("Closing TcpStream with address {:?} after echoing {} bytes ({} short writes, at most {} bytes buffered)",
+                                                                 self.socket_addr, self.bytes_echoed, self.short_writes, self.peak_pending), false);
+                    pool.give(buffer);
+                    return false
+                },
+                Ok(bytes_read) if self.greeting => {
+                    let open = self.greet(&buffer[..bytes_read], allocations);
+                    pool.give(buffer);
+                    if !open {
+                        return false
+                    }
+                },
+                Ok(bytes_read) => {
+                    pretty_print("DBG", "Echo Server", &format!("Echoing {} bytes: {:?}", bytes_read, &buffer[0..min(bytes_read, 4)]), false);
+                    if let Some(ref counters) = self.counters {
+                        counters.tcp_bytes_received.fetch_add(bytes_read as u64, Ordering::Relaxed);
+                    }
+                    self.echoed = 0;
+                    self.read = bytes_read;
+                    self.peak_pending = self.peak_pending.max(bytes_read);
+                    self.buffer = Some(buffer);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    pool.give(buffer);
+                    return true
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => pool.give(buffer),
+                Err(e) => {
+                    pretty_print("ERR", "Echo Server", &format!("Failed to read from socket with address {:?}, encountered error '{}'", self.socket_addr, e), false);
+                    pool.give(buffer);
+                    return false
+                },
+            }
         }
     }
 
-    // If the udp thread has closed
-    if let Err(e) = udp_send.send(()) {
-        if let Ok(Err(e)) = udp_handle.join() {
-            pretty_print("ERR", "UDP Thread", &format!("UDP thread encountered error '{:?}' while executing", e), false);
-        } else {
-            pretty_print("ERR", "UDP Thread", &format!("Failed send kill signal to UDP thread, encountered error '{:?}'", e), false);
+    /// Looks for [CONTROL_MAGIC] or [DATA_MAGIC] and a test's token at the start of the
+    /// connection. Anything else is queued to be echoed. Returns false if the connection should be
+    /// closed, or handed over to another thread.
+    fn greet(&mut self, data: &[u8], allocations: &Allocations) -> bool {
+        for (i, &byte) in data.iter().enumerate() {
+            if !self.greeting {
+                self.unsent.extend_from_slice(&data[i..]);
+                if let Some(ref counters) = self.counters {
+                    counters.tcp_bytes_received.fetch_add((data.len() - i) as u64, Ordering::Relaxed);
+                }
+                return true
+            }
+
+            self.held.push(byte);
+            if self.held == CONTROL_MAGIC {
+                pretty_print("LOG", "Echo Server", &format!("TcpStream with address {:?} is a control connection", self.socket_addr), false);
+                self.handover = Some(Handover::Session);
+                self.held = data[i + 1..].to_vec();
+                return false
+            }
+
+            let len = self.held.len();
+            if len > DATA_MAGIC.len() && self.held.starts_with(DATA_MAGIC) {
+                if byte == b'\n' {
+                    let token = String::from_utf8_lossy(&self.held[DATA_MAGIC.len()..len - 1]).into_owned();
+                    let allocated = match token.parse().ok().and_then(|token| allocations.data_connection(token)) {
+                        Some(allocated) => allocated,
+                        None => {
+                            pretty_print("ERR", "Echo Server", &format!("Closing TcpStream with address {:?}, which is a data connection of unknown test '{}'",
+                                                                         self.socket_addr, token), false);
+                            return false
+                        },
+                    };
+                    allocated.counters.tcp_connections.fetch_add(1, Ordering::Relaxed);
+                    self.held.clear();
+                    self.greeting = false;
+                    if allocated.test.spec().direction != Direction::Echo {
+                        self.handover = Some(Handover::Stream(allocated));
+                        self.held = data[i + 1..].to_vec();
+                        return false
+                    }
+                    self.counters = Some(allocated.counters);
+                } else if len >= MAX_DATA_GREETING_LEN {
+                    self.unsent.append(&mut self.held);
+                    self.greeting = false;
+                }
+                continue
+            }
+
+            let is_start_of = |magic: &[u8]| magic.starts_with(&self.held);
+            if !is_start_of(CONTROL_MAGIC) && !is_start_of(DATA_MAGIC) {
+                self.unsent.append(&mut self.held);
+                self.greeting = false;
+            }
         }
-    } else {
-        if let Err(e) = udp_handle.join() {
-            pretty_print("ERR", "UDP Thread", &format!("Failed to join with UDP thread, encountered error {:?}", e), false);
-        } else {
-            pretty_print("LOG", "UDP Thread", "Successfully closed UDP thread.", false);
+        true
+    }
+
+    /// Counts bytes that were echoed. They're counted towards the test the connection is for by
+    /// [write_counted].
+    fn count_echoed(&mut self, bytes: usize) {
+        self.bytes_echoed += bytes as u64;
+    }
+
+    /// Stops echoing, and serves the connection from another thread as [handover] says.
+    fn hand_over(mut self, handover: Handover, registry: &Registry, pool: &mut BufferPool, summary: &mut EchoSummary, handed_over: &HandedOver) {
+        let _ = registry.deregister(&mut self.stream);
+        if let Some(buffer) = self.buffer.take() {
+            pool.give(buffer);
+        }
+        summary.tcp_bytes_echoed += self.bytes_echoed;
+        summary.short_writes += self.short_writes;
+
+        let socket_addr = self.socket_addr;
+        let held = self.held;
+        let stream = into_std(self.stream);
+        let count = handed_over.count.clone();
+        let allocations = handed_over.allocations.clone();
+        let timeout = handed_over.timeout;
+        let max_connections = handed_over.max_connections;
+        count.fetch_add(1, Ordering::SeqCst);
+        match handover {
+            Handover::Session => {
+                summary.sessions_served += 1;
+                thread::spawn(move || {
+                    match control::serve_session(stream, &held, &allocations, max_connections, timeout) {
+                        Ok(stats) => pretty_print("LOG", "Echo Server", &format!("Closing control connection with address {:?} after a session that sent {} bytes and echoed {} datagrams",
+                                                                                  socket_addr, stats.tcp_bytes_sent, stats.datagrams_echoed), false),
+                        Err(e) => pretty_print("ERR", "Echo Server", &format!("Closing control connection with address {:?}, encountered error '{}'", socket_addr, e), false),
+                    }
+                    count.fetch_sub(1, Ordering::SeqCst);
+                });
+            },
+            Handover::Stream(allocated) => {
+                summary.streams_served += 1;
+                thread::spawn(move || {
+                    match control::serve_stream(stream, allocated.test.spec(), &held, &allocated.counters, timeout) {
+                        Ok((sent, received)) => pretty_print("LOG", "Echo Server", &format!("Closing TcpStream with address {:?} after streaming {} bytes to it and receiving {} bytes",
+                                                                                             socket_addr, sent, received), false),
+                        Err(e) => pretty_print("ERR", "Echo Server", &format!("Failed to stream to {:?}, encountered error '{}'", socket_addr, e), false),
+                    }
+                    count.fetch_sub(1, Ordering::SeqCst);
+                });
+            },
         }
     }
 
-    Ok(())
+    /// Stops serving the connection, returning its buffer to the pool if it has one, and adds
+    /// what was echoed over it to [summary].
+    fn close(mut self, registry: &Registry, pool: &mut BufferPool, summary: &mut EchoSummary) {
+        let _ = registry.deregister(&mut self.stream);
+        if let Some(buffer) = self.buffer.take() {
+            pool.give(buffer);
+        }
+        summary.tcp_bytes_echoed += self.bytes_echoed;
+        summary.short_writes += self.short_writes;
+    }
+
+    fn set_waiting_to_write(&mut self, registry: &Registry, token: Token, waiting: bool) -> bool {
+        if self.waiting_to_write == waiting {
+            return true
+        }
+        self.waiting_to_write = waiting;
+        let interest = if waiting { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+        registry.reregister(&mut self.stream, token, interest).is_ok()
+    }
+}
+
+/// Connections that were handed over to threads of their own, which still count towards
+/// [config.echo_max_connections], and what those threads need.
+struct HandedOver {
+    count: Arc<AtomicUsize>,
+    allocations: Arc<Allocations>,
+    max_connections: usize,
+    /// How long a handed over connection can go without making progress before it's closed. Control
+    /// connections only use it for writes, since they're idle for as long as each test runs
+    timeout: Option<Duration>,
+}
+
+/// Writes as much of [bytes] to [stream] as it takes, counting it towards the test [counters] are
+/// for. It's counted before it's written, since the client can read the echo and ask for the
+/// test's counters before the write returns, and what wasn't written is taken back off after.
+fn write_counted(stream: &mut TcpStream, bytes: &[u8], counters: Option<&Arc<TestCounters>>) -> io::Result<usize> {
+    if let Some(counters) = counters {
+        counters.tcp_bytes_sent.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    }
+    let result = stream.write(bytes);
+    if let Some(counters) = counters {
+        let unwritten = bytes.len() - *result.as_ref().unwrap_or(&0);
+        counters.tcp_bytes_sent.fetch_sub(unwritten as u64, Ordering::Relaxed);
+    }
+    result
+}
 
+/// Turns a connection the event loop was serving back into a blocking standard library one.
+#[cfg(unix)]
+fn into_std(stream: TcpStream) -> std::net::TcpStream {
+    use std::os::unix::io::{ FromRawFd, IntoRawFd };
+    unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) }
 }
 
-#[allow(deprecated)]
-pub fn tcp_echo(exit_recv: Receiver<()>) -> Result<(), io::Error> {
-    let tcp = match TcpListener::bind(ECHO_SERVER_TCP_IP) {
+/// Turns a connection the event loop was serving back into a blocking standard library one.
+#[cfg(windows)]
+fn into_std(stream: TcpStream) -> std::net::TcpStream {
+    use std::os::windows::io::{ FromRawSocket, IntoRawSocket };
+    unsafe { std::net::TcpStream::from_raw_socket(stream.into_raw_socket()) }
+}
+
+/// Accepts TCP connections on [config.echo_server_tcp_ip], and echoes everything sent over each
+/// connection back. Every connection is served from a single event loop, so an echo is sent as
+/// soon as the data arrives. At most [config.echo_max_connections] are served at once;
+/// connections beyond that are closed immediately. When the kill signal is received every open
+/// connection is closed.
+///
+/// Control connections, and data connections of reverse and bidirectional tests, are handed over
+/// to their own threads. Data connections of other tests are echoed like any other connection,
+/// but what's echoed is counted towards the test.
+pub fn tcp_echo(config: Config, mut poll: Poll, exit_recv: Receiver<()>, allocations: Arc<Allocations>) -> Result<EchoSummary, io::Error> {
+    let tcp_ip = config.echo_server_tcp_ip;
+    let mut tcp = match TcpListener::bind(tcp_ip) {
         Ok(x) => x,
         Err(e) => {
             pretty_print("ERR", "Echo Server",
-                         &format!("Failed to create TcpListener with ip {}, encountered error '{}'", ECHO_SERVER_TCP_IP, e), false);
+                         &format!("Failed to create TcpListener with ip {}, encountered error '{}'", tcp_ip, e), false);
             return Err(e)
         }
     };
-    tcp.set_nonblocking(true)?;
+    poll.registry().register(&mut tcp, SOCKET, Interest::READABLE)?;
 
-    // 64 MB buffer
-    let mut buffer = vec![0u8; 1024 * 1024 * 64];
+    let mut pool = BufferPool::new(config.echo_tcp_chunk_size);
+
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = FIRST_CONNECTION;
+    let mut summary = EchoSummary::default();
+    let handed_over = HandedOver {
+        count: Arc::new(AtomicUsize::new(0)),
+        allocations: allocations.clone(),
+        max_connections: config.echo_max_connections,
+        timeout: config.echo_idle_timeout,
+    };
 
     loop {
-        if let Ok((mut tcp_stream, socket_addr)) = tcp.accept() {
-            tcp_stream.set_nonblocking(false)?;
-            tcp_stream.set_read_timeout(None)?;
-            loop {
-                if let Ok(bytes_read) = tcp_stream.read(&mut buffer) {
-                    if bytes_read == 0 {
-                        pretty_print("LOG", "Echo Server", &format!("Closing TcpStream with address {:?}", socket_addr), false);
-                        break
+        // Only wake up on a timer if connections have to be closed for being idle
+        let poll_timeout = config.echo_idle_timeout.map(|idle_timeout| {
+            connections.values()
+                .map(|c| (c.last_activity + idle_timeout).saturating_duration_since(Instant::now()))
+                .min()
+                .unwrap_or(idle_timeout)
+        });
+        match poll.poll(&mut events, poll_timeout) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {
+                    if exit_recv.try_recv().is_ok() {
+                        for (_, connection) in connections.drain() {
+                            pretty_print("LOG", "Echo Server", &format!("Closing TcpStream with address {:?}", connection.socket_addr), false);
+                            connection.close(poll.registry(), &mut pool, &mut summary);
+                        }
+                        pretty_print("LOG", "TCP Memory", &format!("Peak buffer memory {} bytes ({} buffers of {} bytes, at most {} in use at once)",
+                                                                    pool.peak_memory(), pool.allocated, pool.buffer_len, pool.peak_in_use), false);
+                        return Ok(summary)
                     }
-                    match tcp_stream.write(&buffer[0..bytes_read]) {
-                        Ok(_) => pretty_print("LOG", "Echo Server", &format!("Successfully echoed {} bytes: {:?}", bytes_read, &buffer[0..min(bytes_read, 4)]), false),
-                        _ => pretty_print("ERR", "Echo Server", &format!("Failed to echo {} bytes back", bytes_read), false),
+                },
+                SOCKET => loop {
+                    let (mut stream, socket_addr) = match tcp.accept() {
+                        Ok(x) => x,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            pretty_print("ERR", "Echo Server", &format!("Failed to accept a connection, encountered error '{}'", e), false);
+                            break
+                        },
+                    };
+                    if connections.len() + handed_over.count.load(Ordering::SeqCst) >= config.echo_max_connections {
+                        pretty_print("ERR", "Echo Server",
+                                     &format!("Refusing connection from {:?}, already serving the maximum of {} connections",
+                                              socket_addr, config.echo_max_connections), false);
+                        continue
                     }
-                } else {
-                    pretty_print("ERR", "Echo Server", &format!("Failed to read any bytes from socket with address {:?}", socket_addr), false);
-                }
-                // To reduce CPU usage
-                thread::sleep_ms(1);
+
+                    let token = next_token;
+                    next_token = Token(next_token.0 + 1);
+                    poll.registry().register(&mut stream, token, Interest::READABLE)?;
+                    pretty_print("LOG", "Echo Server", &format!("Accepted TcpStream with address {:?}", socket_addr), false);
+                    connections.insert(token, Connection::new(stream, socket_addr));
+                    summary.connections_served += 1;
+                },
+                token => {
+                    let open = match connections.get_mut(&token) {
+                        Some(connection) => connection.echo(poll.registry(), token, &mut pool, &allocations),
+                        None => continue,
+                    };
+                    if !open {
+                        if let Some(mut connection) = connections.remove(&token) {
+                            match connection.handover.take() {
+                                Some(handover) => connection.hand_over(handover, poll.registry(), &mut pool, &mut summary, &handed_over),
+                                None => connection.close(poll.registry(), &mut pool, &mut summary),
+                            }
+                        }
+                    }
+                },
             }
         }
-        // So the program doesn destroy the cpu / battery of my laptop
-        thread::sleep_ms(10);
-        if let Ok(_) = exit_recv.try_recv() {
-            return Ok(())
+
+        if let Some(idle_timeout) = config.echo_idle_timeout {
+            let idle: Vec<Token> = connections.iter()
+                .filter(|&(_, c)| c.last_activity.elapsed() >= idle_timeout)
+                .map(|(token, _)| *token)
+                .collect();
+            for token in idle {
+                if let Some(connection) = connections.remove(&token) {
+                    pretty_print("LOG", "Echo Server", &format!("Closing idle TcpStream with address {:?}", connection.socket_addr), false);
+                    connection.close(poll.registry(), &mut pool, &mut summary);
+                }
+            }
         }
     }
 }
 
-#[allow(deprecated)]
-pub fn udp_echo(exit_recv: Receiver<()>) -> Result<(), io::Error> {
-    let udp = match UdpSocket::bind(ECHO_SERVER_UDP_IP) {
+/// Counters for a single peer of the UDP echo server.
+#[derive(Default)]
+struct PeerStats {
+    datagrams_echoed: u64,
+    bytes_echoed: u64,
+    /// Datagrams that weren't echoed because sending them failed
+    datagrams_dropped: u64,
+}
+
+/// Echoes every datagram received on [config.echo_server_udp_ip] back to whoever sent it, as long
+/// as the sender is in one of the [config.echo_allowed_clients] networks (or it is empty).
+/// Probes of a test that a control session started are counted towards the test.
+pub fn udp_echo(config: Config, mut poll: Poll, exit_recv: Receiver<()>, allocations: Arc<Allocations>) -> Result<EchoSummary, io::Error> {
+    let udp_ip = config.echo_server_udp_ip;
+    let allowed_clients = config.echo_allowed_clients;
+    let mut udp = match UdpSocket::bind(udp_ip) {
         Ok(x) => x,
         Err(e) => {
             pretty_print("ERR", "Echo Server",
-                         &format!("Failed to create UdpSocket with ip {}, encountered error '{}'", ECHO_SERVER_UDP_IP, e), false);
+                         &format!("Failed to create UdpSocket with ip {}, encountered error '{}'", udp_ip, e), false);
             return Err(e)
         }
     };
 
-    let _ = udp.set_read_timeout(Some(Duration::from_secs(1)));
+    poll.registry().register(&mut udp, SOCKET, Interest::READABLE)?;
 
-    // We want to send data back to the actual udp_dst
-    let udp_dst = UDP_IP.parse::<SocketAddr>().unwrap();
+    // Datagrams can't be any larger than this, so one buffer is all the UDP echo server needs
+    let mut buffer = vec![0u8; UDP_BUFFER_LEN];
 
-    // 64 MB will be way more than enough
-    let mut buffer = vec![0u8; 1024 * 1024 * 64];
+    // Only allowed peers get an entry, so sending from spoofed addresses can't make this grow
+    let mut peers: HashMap<SocketAddr, PeerStats> = HashMap::new();
+    // Datagrams from peers that aren't allowed, which are never echoed
+    let mut disallowed: u64 = 0;
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
 
-    // Keep trying to receive data until we get the kill signal, then return
+    // Echo datagrams as they arrive until we get the kill signal, then return
     loop {
+        match poll.poll(&mut events, None) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
 
-        if let Ok((bytes_read, _socket_addr)) = udp.recv_from(&mut buffer) {
-            match udp.send_to(&buffer[0..bytes_read], &udp_dst) {
-                Ok(_)   => pretty_print("LOG", "Echo Server", &format!("Successfully echoed {} bytes: {:?}", bytes_read, &buffer[0..min(bytes_read, 4)]), false),
-                _       => pretty_print("ERR", "Echo Server", &format!("Failed to echo {} bytes back", bytes_read), false),
+        for event in events.iter() {
+            if event.token() == WAKER && exit_recv.try_recv().is_ok() {
+                for (peer, stats) in peers.iter() {
+                    pretty_print("LOG", "UDP Peer", &format!("{}: echoed {} datagrams ({} bytes), dropped {} datagrams",
+                                                             peer, stats.datagrams_echoed, stats.bytes_echoed, stats.datagrams_dropped), false);
+                }
+                if disallowed > 0 {
+                    pretty_print("LOG", "UDP Peer", &format!("Ignored {} datagrams from clients that aren't allowed", disallowed), false);
+                }
+                pretty_print("LOG", "UDP Memory", &format!("Peak buffer memory {} bytes", buffer.len()), false);
+                return Ok(EchoSummary {
+                    datagrams_echoed: peers.values().map(|stats| stats.datagrams_echoed).sum(),
+                    udp_bytes_echoed: peers.values().map(|stats| stats.bytes_echoed).sum(),
+                    ..EchoSummary::default()
+                })
+            }
+            if event.token() != SOCKET {
+                continue
             }
-        }
 
-        if let Ok(_) = exit_recv.try_recv() {
-            return Ok(())
+            loop {
+                let (bytes_read, peer) = match udp.recv_from(&mut buffer) {
+                    Ok(x) => x,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        pretty_print("ERR", "Echo Server", &format!("Failed to receive a datagram, encountered error '{}'", e), false);
+                        break
+                    },
+                };
+                let counters = ProbeHeader::read(&buffer[0..bytes_read]).and_then(|header| allocations.datagram_counters(header.test_id));
+                if let Some(ref counters) = counters {
+                    counters.datagrams_received.fetch_add(1, Ordering::Relaxed);
+                }
+                if !allowed_clients.is_empty() && !allowed_clients.iter().any(|n| n.contains(peer.ip())) {
+                    let level = if disallowed == 0 { "LOG" } else { "DBG" };
+                    pretty_print(level, "Echo Server", &format!("Ignoring a datagram from {}, which is not an allowed client", peer), false);
+                    disallowed += 1;
+                    continue
+                }
+                let stats = peers.entry(peer).or_default();
+                // The echo is counted towards its test before it's sent, since the client can
+                // receive it and ask for the test's counters before send_to even returns
+                if let Some(ref counters) = counters {
+                    counters.datagrams_echoed.fetch_add(1, Ordering::Relaxed);
+                    counters.udp_bytes_echoed.fetch_add(bytes_read as u64, Ordering::Relaxed);
+                }
+                // If the send buffer is full the datagram is dropped, just like the network would
+                match udp.send_to(&buffer[0..bytes_read], peer) {
+                    Ok(_)   => {
+                        stats.datagrams_echoed += 1;
+                        stats.bytes_echoed += bytes_read as u64;
+                        pretty_print("DBG", "Echo Server", &format!("Successfully echoed {} bytes to {}: {:?}", bytes_read, peer, &buffer[0..min(bytes_read, 4)]), false)
+                    },
+                    _       => {
+                        if let Some(ref counters) = counters {
+                            counters.datagrams_echoed.fetch_sub(1, Ordering::Relaxed);
+                            counters.udp_bytes_echoed.fetch_sub(bytes_read as u64, Ordering::Relaxed);
+                        }
+                        stats.datagrams_dropped += 1;
+                        pretty_print("ERR", "Echo Server", &format!("Failed to echo {} bytes back to {}", bytes_read, peer), false)
+                    },
+                }
+            }
         }
-
-        thread::sleep_ms(1);
     }
-
-}
\ No newline at end of file
+}
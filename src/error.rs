@@ -0,0 +1,97 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+/// Why a test, or a single message of a test, failed.
+#[derive(Debug)]
+pub enum TestError {
+    /// Nothing was echoed back before the timeout
+    Timeout,
+    Io(io::Error),
+    /// A datagram was received from somewhere other than the echo server
+    WrongPeer(SocketAddr),
+    /// The echo differs from what was sent, starting at byte [offset]
+    PayloadMismatch { offset: usize },
+    /// The echo server didn't complete the control protocol handshake, or refused a request made
+    /// over the control connection
+    Handshake(String),
+    /// Only part of the echo arrived before the timeout, or before the connection was closed
+    ShortRead { expected: usize, received: usize },
+}
+
+impl TestError {
+    pub fn cause(&self) -> FailureCause {
+        match *self {
+            TestError::Timeout => FailureCause::Timeout,
+            TestError::Io(_) => FailureCause::Io,
+            TestError::WrongPeer(_) => FailureCause::WrongPeer,
+            TestError::PayloadMismatch { .. } => FailureCause::PayloadMismatch,
+            TestError::Handshake(_) => FailureCause::Handshake,
+            TestError::ShortRead { .. } => FailureCause::ShortRead,
+        }
+    }
+}
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TestError::Timeout => write!(f, "timed out"),
+            TestError::Io(ref e) => write!(f, "{}", e),
+            TestError::WrongPeer(ref peer) => write!(f, "received a datagram from {} instead of the echo server", peer),
+            TestError::PayloadMismatch { offset } => write!(f, "the echo differs from the message at byte {}", offset),
+            TestError::Handshake(ref e) => write!(f, "handshake failed: {}", e),
+            TestError::ShortRead { expected, received } => write!(f, "received {} of {} bytes", received, expected),
+        }
+    }
+}
+
+/// Reads that run past a socket's timeout fail with WouldBlock or TimedOut (depending on the
+/// platform), so those become [TestError::Timeout].
+impl From<io::Error> for TestError {
+    fn from(e: io::Error) -> TestError {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => TestError::Timeout,
+            _ => TestError::Io(e),
+        }
+    }
+}
+
+/// The kind of a [TestError], without any details, so failures can be counted by cause.
+#[derive(Serialize, Deserialize, Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCause {
+    Timeout,
+    Io,
+    WrongPeer,
+    PayloadMismatch,
+    Handshake,
+    ShortRead,
+}
+
+impl fmt::Display for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FailureCause::Timeout => write!(f, "timeout"),
+            FailureCause::Io => write!(f, "io error"),
+            FailureCause::WrongPeer => write!(f, "wrong peer"),
+            FailureCause::PayloadMismatch => write!(f, "payload mismatch"),
+            FailureCause::Handshake => write!(f, "handshake failure"),
+            FailureCause::ShortRead => write!(f, "short read"),
+        }
+    }
+}
+
+/// A message that wasn't echoed back properly, and why.
+#[derive(Serialize, Deserialize, Debug, Hash, Clone)]
+pub struct DroppedMessage {
+    pub message_number: u32,
+    pub cause: FailureCause,
+    /// A description of the failure
+    pub detail: String,
+}
+
+impl DroppedMessage {
+    pub fn new(message_number: u32, error: &TestError) -> DroppedMessage {
+        DroppedMessage { message_number, cause: error.cause(), detail: error.to_string() }
+    }
+}
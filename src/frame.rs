@@ -0,0 +1,82 @@
+/// The length of the header at the start of every message of a TCP test.
+pub const FRAME_HEADER_LEN: usize = 12;
+
+/// Every frame starts with this, so the start of the next frame can be found again after losing
+/// track of where the echo is, like when an echo is only partly read before timing out.
+pub const FRAME_MAGIC: &[u8] = b"DL1F";
+
+/// The header at the start of every message a TCP test sends, so each echo can be matched to the
+/// message it's an echo of no matter how much of the stream was read before it. Every field is
+/// big endian, and the payload follows it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    /// The message number
+    pub seq: u32,
+    /// The length of the payload after the header
+    pub len: u32,
+}
+
+impl FrameHeader {
+    /// Writes the header to the start of [frame].
+    pub fn write(&self, frame: &mut [u8]) {
+        frame[0..4].copy_from_slice(FRAME_MAGIC);
+        frame[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        frame[8..12].copy_from_slice(&self.len.to_be_bytes());
+    }
+
+    /// Reads the header from the start of [bytes], if they start with one.
+    pub fn read(bytes: &[u8]) -> Option<FrameHeader> {
+        if bytes.len() < FRAME_HEADER_LEN || &bytes[0..4] != FRAME_MAGIC {
+            return None
+        }
+        let mut seq = [0u8; 4];
+        let mut len = [0u8; 4];
+        seq.copy_from_slice(&bytes[4..8]);
+        len.copy_from_slice(&bytes[8..12]);
+        Some(FrameHeader { seq: u32::from_be_bytes(seq), len: u32::from_be_bytes(len) })
+    }
+
+    /// How many bytes at the start of [bytes] can't be the start of a frame, so can be skipped
+    /// when looking for the next header.
+    pub fn skippable(bytes: &[u8]) -> usize {
+        (1..bytes.len())
+            .find(|&i| {
+                let rest = &bytes[i..];
+                let len = rest.len().min(FRAME_MAGIC.len());
+                rest[..len] == FRAME_MAGIC[..len]
+            })
+            .unwrap_or(bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = FrameHeader { seq: 9, len: 1400 };
+        let mut frame = [0u8; FRAME_HEADER_LEN];
+        header.write(&mut frame);
+        assert_eq!(FrameHeader::read(&frame), Some(header));
+    }
+
+    #[test]
+    fn partial_headers_and_garbage_arent_read() {
+        let mut frame = [0u8; FRAME_HEADER_LEN];
+        FrameHeader { seq: 1, len: 8 }.write(&mut frame);
+        assert!(FrameHeader::read(&frame[..FRAME_HEADER_LEN - 1]).is_none());
+        assert!(FrameHeader::read(b"DL1Xaaaabbbb").is_none());
+    }
+
+    #[test]
+    fn skips_up_to_where_a_header_could_start() {
+        assert_eq!(FrameHeader::skippable(b"xyzDL1F\0\0\0\0\0\0\0\0"), 3);
+        // A header that was cut off could carry on in the bytes that haven't been read yet
+        assert_eq!(FrameHeader::skippable(b"abcDL"), 3);
+        assert_eq!(FrameHeader::skippable(b"DLDL1F"), 2);
+        assert_eq!(FrameHeader::skippable(b"garbage"), 7);
+        // The bytes at the start are never a header, or they wouldn't be skipped
+        assert_eq!(FrameHeader::skippable(b"DL1Fxxxx"), 8);
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{ BufRead, BufReader, self };
+use std::path::Path;
+use std::time::Duration;
+
+use csv::{ ReaderBuilder, StringRecord };
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use test::*;
+use error::{ DroppedMessage, FailureCause };
+use output::{ self, LogRecord, RunDocument, SampleRow, SampleStatus, SummaryRow, TestRecord };
+use util::pretty_print;
+
+/// The first header of the CSV layout written before the schema version column was added, which
+/// has a table of tests followed by a table of messages in one file.
+const LEGACY_HEADER: &str = "Transfer Protocall";
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::Io(ref e) => write!(f, "failed to read results: {}", e),
+            ImportError::Parse(ref e) => write!(f, "failed to parse results: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> ImportError {
+        ImportError::Io(e)
+    }
+}
+
+impl From<csv::Error> for ImportError {
+    fn from(e: csv::Error) -> ImportError {
+        if e.is_io_error() {
+            if let csv::ErrorKind::Io(e) = e.into_kind() {
+                return ImportError::Io(e)
+            }
+            unreachable!("is_io_error means the kind is Io")
+        }
+        ImportError::Parse(e.to_string())
+    }
+}
+
+/// Loads the results of every test that succeeded from a file saved in any format. Files ending
+/// in `.json` are read as a JSON document and `.jsonl` as a JSON Lines log. Anything else is read
+/// as CSV, either a summary file (along with its samples file, if there is one) or the older
+/// layout with two tables in one file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<TestData>, ImportError> {
+    let path = path.as_ref();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => load_json(path),
+        Some("jsonl") => load_json_lines(path),
+        _ => load_csv(path),
+    }
+}
+
+/// What a bulk test streamed, when all that's known is how much it received in total.
+fn total_throughput(bytes_received: u64, duration: Duration) -> Throughput {
+    Throughput { bytes_sent: 0, bytes_received, duration, interval: duration, interval_bytes: vec![bytes_received] }
+}
+
+/// The round trip time of every message in [samples] and the ones that were dropped, in order.
+fn from_samples(samples: &mut [&SampleRow]) -> (Vec<Option<Duration>>, Vec<DroppedMessage>) {
+    samples.sort_by_key(|sample| sample.message_index);
+    let durations = samples.iter().map(|sample| sample.rtt_ns.map(Duration::from_nanos)).collect();
+    let dropped = samples.iter()
+        .filter(|sample| sample.status == SampleStatus::Dropped)
+        .map(|sample| DroppedMessage {
+            message_number: sample.message_index,
+            cause: sample.failure_cause.unwrap_or(FailureCause::Timeout),
+            detail: sample.failure_reason.clone().unwrap_or_default(),
+        })
+        .collect();
+    (durations, dropped)
+}
+
+/// Fills in [data] from the [summary] of a test whose messages aren't known, so its results at
+/// least have the right totals: every message that was echoed took the mean round trip time, and
+/// the rest timed out, so they count as lost.
+fn from_summary(data: &mut TestData, summary: &Summary) {
+    let mean = summary.mean_rtt.unwrap_or_default();
+    data.individual_durations = (0..summary.messages).map(|i| if i < summary.successful { Some(mean) } else { None }).collect();
+    data.dropped_messages = (summary.successful..summary.messages)
+        .map(|i| DroppedMessage { message_number: i as u32, cause: FailureCause::Timeout, detail: "unknown".to_string() })
+        .collect();
+}
+
+fn load_json(path: &Path) -> Result<Vec<TestData>, ImportError> {
+    let document: RunDocument = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| ImportError::Parse(e.to_string()))?;
+    Ok(document.tests.into_iter()
+        .filter_map(|record| {
+            let label = record.label;
+            record.data.map(|data| TestData { label, ..data })
+        })
+        .collect())
+}
+
+fn load_json_lines(path: &Path) -> Result<Vec<TestData>, ImportError> {
+    let mut messages: BTreeMap<usize, Vec<SampleRow>> = BTreeMap::new();
+    let mut tests: Vec<TestRecord> = vec![];
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        match serde_json::from_str(&line).map_err(|e| ImportError::Parse(format!("line {}: {}", i + 1, e)))? {
+            LogRecord::Message(sample) => messages.entry(sample.test_index).or_default().push(sample),
+            LogRecord::Test(record) => tests.push(*record),
+            LogRecord::Run(_) | LogRecord::End { .. } => {},
+        }
+    }
+
+    // A test that didn't finish (because the run was stopped) has no record
+    Ok(tests.into_iter().filter_map(|record| {
+        let summary = record.summary?;
+        let mut data = TestData { label: record.label, ..TestData::new(record.test, summary.wall_clock, vec![], vec![]) };
+        match messages.get(&record.index) {
+            Some(samples) => {
+                let (durations, dropped) = from_samples(&mut samples.iter().collect::<Vec<_>>());
+                data.individual_durations = durations;
+                data.dropped_messages = dropped;
+            },
+            None => from_summary(&mut data, &summary),
+        }
+        if data.test.spec().is_bulk() {
+            data.throughput = Some(total_throughput(summary.bytes_echoed, summary.wall_clock));
+        }
+        Some(data)
+    }).collect())
+}
+
+fn read_rows<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, ImportError> {
+    let mut reader = ReaderBuilder::new().from_path(path)?;
+    let rows = reader.deserialize().collect::<Result<Vec<T>, csv::Error>>()?;
+    Ok(rows)
+}
+
+fn load_csv(path: &Path) -> Result<Vec<TestData>, ImportError> {
+    let mut reader = ReaderBuilder::new().has_headers(false).flexible(true).from_path(path)?;
+    let records = reader.records().collect::<Result<Vec<StringRecord>, csv::Error>>()?;
+    match records.first().and_then(|header| header.get(0)) {
+        Some("schema_version") => load_summary_csv(path),
+        Some(LEGACY_HEADER) => load_legacy_csv(&records),
+        Some(first) => Err(ImportError::Parse(format!("'{}' isn't the first column of any CSV layout this writes", first))),
+        None => Ok(vec![]),
+    }
+}
+
+/// Loads a summary file, and the round trip time of every message from its samples file. The
+/// rows of the streams of parallel tests are left out, since their messages are in the row of
+/// the whole test, and so are the rows of tests that failed.
+fn load_summary_csv(path: &Path) -> Result<Vec<TestData>, ImportError> {
+    let summaries: Vec<SummaryRow> = read_rows(path)?;
+    let samples_path = output::companion_file(&path.to_string_lossy(), "samples");
+    let samples: Vec<SampleRow> = if Path::new(&samples_path).exists() {
+        read_rows(Path::new(&samples_path))?
+    } else {
+        if summaries.iter().any(|row| row.messages_sent > 0) {
+            pretty_print("LOG", "Import", &format!("'{}' doesn't have a samples file, so every message of it took the mean round trip time", path.display()), false);
+        }
+        vec![]
+    };
+
+    Ok(summaries.into_iter().filter(|row| row.stream.is_none() && row.error.is_none()).map(|row| {
+        let spec = TestSpec { num_messages: row.messages_sent as u32, message_len: row.message_len, direction: row.direction, ..TestSpec::default() };
+        let total_duration = Duration::from_nanos(row.total_time_ns);
+        let mut data = TestData { label: row.test_label.clone(), ..TestData::new(Test::new(row.protocol, spec), total_duration, vec![], vec![]) };
+
+        let mut test_samples: Vec<&SampleRow> = samples.iter()
+            .filter(|sample| sample.run_id == row.run_id && sample.test_index == row.test_index)
+            .collect();
+        if test_samples.is_empty() {
+            from_summary(&mut data, &Summary {
+                wall_clock: total_duration,
+                messages: row.messages_sent,
+                successful: row.messages_echoed,
+                mean_rtt: row.mean_rtt_ns.map(Duration::from_nanos),
+                bytes_echoed: row.bytes_echoed,
+                throughput: row.throughput_bytes_per_sec,
+            });
+        } else {
+            let (durations, dropped) = from_samples(&mut test_samples);
+            data.individual_durations = durations;
+            data.dropped_messages = dropped;
+        }
+        // Only bulk tests have a goodput
+        if row.goodput_bps.is_some() {
+            data.throughput = Some(total_throughput(row.bytes_echoed, total_duration));
+        }
+        data
+    }).collect())
+}
+
+/// Loads the layout written before the schema version column was added: a table with a row for
+/// each test, then a table with a row for every message that was echoed, in the same order. Its
+/// "average time" is really the sum of the round trip times, which is used as the test's duration.
+fn load_legacy_csv(records: &[StringRecord]) -> Result<Vec<TestData>, ImportError> {
+    let is_header = |record: &&StringRecord| record.get(0) == Some(LEGACY_HEADER);
+    let mut tables = records.split(|record| is_header(&record)).skip(1);
+    let tests = tables.next().unwrap_or(&[]);
+    let mut messages = tables.next().unwrap_or(&[]).iter();
+
+    let field = |record: &StringRecord, i: usize, name: &str| -> Result<String, ImportError> {
+        record.get(i).map(str::to_string).ok_or_else(|| ImportError::Parse(format!("a row is missing its {}", name)))
+    };
+    let number = |record: &StringRecord, i: usize, name: &str| -> Result<f64, ImportError> {
+        let value = field(record, i, name)?;
+        value.trim().parse::<f64>().map_err(|_| ImportError::Parse(format!("'{}' isn't a valid {}", value, name)))
+    };
+
+    let mut results = vec![];
+    for record in tests {
+        let protocol: Protocol = field(record, 0, "protocol")?.parse().map_err(ImportError::Parse)?;
+        let num_messages = number(record, 1, "number of messages")? as usize;
+        let message_len = number(record, 2, "data size")? as usize;
+        let dropped = if record.get(5).is_some_and(|dropped| !dropped.is_empty()) { number(record, 5, "dropped messages")? as usize } else { 0 };
+
+        let mut durations = vec![];
+        for _ in 0..num_messages.saturating_sub(dropped) {
+            let message = messages.next().ok_or_else(|| ImportError::Parse(format!("the messages of {}:{}:{} are missing", protocol, num_messages, message_len)))?;
+            if field(message, 0, "protocol")? != protocol.to_string() || number(message, 1, "data size")? as usize != message_len {
+                return Err(ImportError::Parse(format!("the messages of {}:{}:{} aren't in the same order as the tests", protocol, num_messages, message_len)))
+            }
+            durations.push(Some(Duration::from_secs_f64(number(message, 2, "time")?)));
+        }
+        let total_duration = durations.iter().flatten().sum();
+        // Which messages were dropped, and why, wasn't saved. They count as lost by timing out
+        let dropped_messages = (durations.len()..num_messages)
+            .map(|i| DroppedMessage { message_number: i as u32, cause: FailureCause::Timeout, detail: "unknown".to_string() })
+            .collect();
+        durations.resize(num_messages, None);
+
+        let spec = TestSpec { num_messages: num_messages as u32, message_len, ..TestSpec::default() };
+        results.push(TestData::new(Test::new(protocol, spec), total_duration, durations, dropped_messages));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    use error::TestError;
+    use output::{ RunLog, RunMetadata };
+
+    /// A file in the temporary directory only this test uses.
+    fn temp_file(name: &str) -> String {
+        env::temp_dir().join(format!("dl1-import-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    /// A TCP test of 5 messages, the third of which timed out.
+    fn tcp_test() -> TestData {
+        let durations = vec![Some(Duration::from_millis(1)), Some(Duration::from_millis(2)), None,
+                             Some(Duration::from_millis(4)), Some(Duration::from_millis(5))];
+        let spec = TestSpec { num_messages: 5, message_len: 64, ..TestSpec::default() };
+        TestData::new(Test::TcpTest(spec), Duration::from_millis(20), durations, vec![DroppedMessage::new(2, &TestError::Timeout)])
+    }
+
+    fn assert_loaded(loaded: &[TestData], saved: &TestData) {
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].test.to_string(), saved.test.to_string());
+        assert_eq!(loaded[0].individual_durations, saved.individual_durations);
+        assert_eq!(loaded[0].dropped_messages.iter().map(|dropped| dropped.message_number).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(loaded[0].loss_percent(), saved.loss_percent());
+    }
+
+    fn metadata() -> RunMetadata {
+        RunMetadata {
+            run_id: "run".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_line: vec![],
+            started_unix_ms: 0,
+            finished_unix_ms: Some(1),
+            echo_server_tcp: "127.0.0.1:12710".parse().unwrap(),
+            echo_server_udp: "127.0.0.1:2710".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn loads_the_legacy_layout() {
+        let filename = temp_file("legacy.csv");
+        fs::write(&filename, "\
+Transfer Protocall,number of messages,data size (bytes),average time (s),average throughput (bytes / sec),dropped messages
+tcp,2,64,0.3,426.6,0
+udp,3,1,0.2,10.0,1
+Transfer Protocall,data size (bytes),time (s),throughput (bytes / s),,
+tcp,64,0.1,640.0,,
+tcp,64,0.2,320.0,,
+udp,1,0.05,20.0,,
+udp,1,0.15,6.6,,
+").unwrap();
+        let loaded = load(&filename);
+        let _ = fs::remove_file(&filename);
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].test.to_string(), "tcp:2:64");
+        assert_eq!(loaded[0].individual_durations, vec![Some(Duration::from_millis(100)), Some(Duration::from_millis(200))]);
+        assert_eq!(loaded[0].total_duration, Duration::from_millis(300));
+
+        // The last message was dropped, and counts as lost
+        assert_eq!(loaded[1].individual_durations, vec![Some(Duration::from_millis(50)), Some(Duration::from_millis(150)), None]);
+        assert_eq!(loaded[1].dropped_messages.len(), 1);
+        assert_eq!(loaded[1].dropped_messages[0].message_number, 2);
+        assert!(loaded[1].loss_percent() > 33.0);
+    }
+
+    #[test]
+    fn rejects_a_legacy_file_with_missing_messages() {
+        let filename = temp_file("missing.csv");
+        fs::write(&filename, "\
+Transfer Protocall,number of messages,data size (bytes),average time (s),average throughput (bytes / sec),dropped messages
+tcp,3,64,0.3,426.6,0
+Transfer Protocall,data size (bytes),time (s),throughput (bytes / s),,
+tcp,64,0.1,640.0,,
+tcp,64,0.2,320.0,,
+").unwrap();
+        let loaded = load(&filename);
+        let _ = fs::remove_file(&filename);
+        match loaded {
+            Err(ImportError::Parse(e)) => assert!(e.contains("missing"), "{}", e),
+            result => panic!("expected the missing message to be reported, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn loads_saved_json() {
+        let filename = temp_file("results.json");
+        let saved = tcp_test();
+        let document = RunDocument { metadata: metadata(), tests: vec![TestRecord::new(0, None, saved.test.clone(), Ok(tcp_test()))] };
+        output::save_json(&document, &filename).unwrap();
+        let loaded = load(&filename);
+        let _ = fs::remove_file(&filename);
+        assert_loaded(&loaded.unwrap(), &saved);
+    }
+
+    #[test]
+    fn loads_saved_csv() {
+        let filename = temp_file("results.csv");
+        let saved = tcp_test();
+        let failed = TestRecord::new(1, None, saved.test.clone(), Err(TestError::Timeout));
+        let written = output::save_csv(&[TestRecord::new(0, None, saved.test.clone(), Ok(tcp_test())), failed], "run", &filename).unwrap();
+        let loaded = load(&filename);
+        for file in written.iter().chain(Some(&filename)) {
+            let _ = fs::remove_file(file);
+        }
+        // The test that failed is left out
+        assert_loaded(&loaded.unwrap(), &saved);
+    }
+
+    #[test]
+    fn loads_a_finished_json_lines_log() {
+        let filename = temp_file("results.jsonl");
+        let saved = tcp_test();
+        let log = Arc::new(RunLog::create(&filename, &metadata(), vec![]).unwrap());
+        let messages = RunLog::messages(&log, 0, &saved.test);
+        for (i, rtt) in saved.individual_durations.iter().enumerate() {
+            messages.record(i as u32, &rtt.ok_or(TestError::Timeout), None);
+        }
+        log.test_finished(0, &saved.test, &Ok(tcp_test()));
+        log.finish(&metadata());
+        let loaded = load(&filename);
+        let _ = fs::remove_file(&filename);
+        assert_loaded(&loaded.unwrap(), &saved);
+    }
+}
@@ -1,145 +1,250 @@
-#![feature(const_fn)]
-
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate serde;
 
 extern crate csv;
+extern crate toml;
+extern crate clap;
+extern crate mio;
+extern crate signal_hook;
+extern crate libc;
 
 mod server;
 mod test;
 mod util;
 mod echo;
 mod config;
+mod cli;
+mod suite;
+mod daemon;
+mod error;
+mod probe;
+mod control;
+mod frame;
+mod stats;
+mod output;
+mod import;
+mod compare;
+mod baseline;
 
 use test::*;
+use suite::Suite;
 
 use util::pretty_print;
+use config::Config;
 
-use std::env;
-
-const USAGE_MESSAGE: &'static str = r#"
-Usage: dl1 [mode] [tests]
+use output::{ Format, RunDocument, RunLog, RunMetadata, TestRecord };
+use baseline::{ Baseline, Thresholds };
 
-modes: serve, echo, required, help
+use std::process;
+use std::sync::Arc;
 
-test format (keep quotes): "[UDP|TCP] [num_messages] [message_len]"
+/// Exit code used when the program arguments or configuration are invalid.
+const EXIT_USAGE: i32 = 2;
+/// Exit code used when the tests couldn't be ran.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code used when results are worse than the baseline.
+const EXIT_REGRESSION: i32 = 3;
 
-"#;
+fn create_server(config: &Config) -> server::Server {
+    match server::Server::new(config) {
+        Ok(s) => s,
+        Err(e) => {
+            pretty_print("ERR", "Server", &format!("Encountered error '{}' while trying to create server.", e), false);
+            process::exit(EXIT_FAILURE)
+        }
+    }
+}
 
-const ECHO: &'static str = "echo";
-const TEST: &'static str = "test";
-const REQ_DATA: &'static str = "required";
+/// Runs [tests], labelling each result with the corresponding label in [labels] if there is one,
+/// and saves the results to [output] in [format] if it is given. A JSON Lines log is written as
+/// the tests run.
+fn run_tests(config: &Config, tests: Vec<Test>, labels: Vec<String>, output: Option<&str>, format: Format) {
+    let tests: Vec<Test> = tests.into_iter().map(|test| {
+        let padded = test.clone().padded();
+        if padded.spec().message_len != test.spec().message_len {
+            pretty_print("LOG", "Test", &format!("{} is ran as {}, since every datagram has a {} byte probe header",
+                                                 test, padded, probe::PROBE_HEADER_LEN), false);
+        }
+        padded
+    }).collect();
+    let mut server = create_server(config);
+    let mut metadata = RunMetadata::start(config);
+
+    let log = match output {
+        Some(output) if format == Format::Jsonl => match RunLog::create(output, &metadata, labels.clone()) {
+            Ok(log) => Some(Arc::new(log)),
+            Err(e) => {
+                pretty_print("ERR", "Output", &format!("Failed to create '{}', encountered error '{}'", output, e), false);
+                process::exit(EXIT_FAILURE)
+            },
+        },
+        _ => None,
+    };
 
-fn test(mut args: Vec<String>) {
-    let mut server;
-    match server::Server::new() {
-        Ok(s) => server = s,
+    let records: Vec<TestRecord> = match server.run_tests(tests.clone(), log.clone()) {
+        Ok(results) => tests.into_iter().zip(results)
+            .zip(labels.into_iter().map(Some).chain(std::iter::repeat(None)))
+            .enumerate()
+            .map(|(index, ((test, result), label))| {
+                if let Err(ref e) = result {
+                    pretty_print("ERR", "Test", &format!("Test failed, encountered error '{}'", e), false);
+                }
+                let result = result.map(|data| TestData { label: label.clone(), ..data });
+                TestRecord::new(index, label, test, result)
+            })
+            .collect(),
         Err(e) => {
-            println!("Encountered error '{}' while trying to create server.", e);
-            return
+            pretty_print("ERR", "Server", &format!("Failed to run tests, encountered error '{}'", e), false);
+            process::exit(EXIT_FAILURE)
         }
     };
+    metadata.finish();
 
-    if args.len() < 3 {
-        pretty_print("LOG", "Program Argument", &format!("No tests provided."), false);
-        return
+    let output = match output {
+        Some(output) => output,
+        None => return,
+    };
+    let saved = match format {
+        Format::Csv => output::save_csv(&records, &metadata.run_id, output),
+        Format::Json => output::save_json(&RunDocument { metadata, tests: records }, output).map(|()| vec![]),
+        Format::Jsonl => {
+            if let Some(log) = log {
+                log.finish(&metadata);
+            }
+            Ok(vec![])
+        },
+    };
+    match saved {
+        Ok(ref others) if others.is_empty() => pretty_print("LOG", "Output", &format!("Wrote results to '{}'", output), false),
+        Ok(others) => pretty_print("LOG", "Output", &format!("Wrote results to '{}' (and {})", output, others.join(", ")), false),
+        Err(e) => {
+            pretty_print("ERR", "Output", &format!("Failed to write results to '{}', encountered error '{}'", output, e), false);
+            process::exit(EXIT_FAILURE)
+        },
     }
+}
 
-    let mut tests: Vec<test::Test> = Vec::with_capacity(args.len() - 2);
-    for arg in &mut args[2..] {
-    arg.replace("\"", "");
-    let tokens: Vec<String> = arg.split(' ').map(|s| s.to_lowercase()).collect();
-    if tokens.len() < 3 {
-        pretty_print("ERR", "Program Argument", &format!("'{}' is not a valid test.", arg), false);
-        continue
-    }
-    let mut num_messages = 0;
-    if let Ok(val) = tokens[1].parse::<u32>() {
-        num_messages += val;
-    } else {
-        pretty_print("ERR", "Program Argument", &format!("'{}' is not a valid number.", tokens[1]), false);
-        continue
-    }
+/// Runs every test in [suite]. The results are saved to [output], or a file named after the suite.
+fn run_suite(config: &Config, suite: Suite, output: Option<&str>, format: Format) {
+    let output = output.map(str::to_string).unwrap_or_else(|| format!("{}.{}", suite.name, format.extension()));
+    pretty_print("LOG", "Suite", &format!("Running suite '{}' ({} tests)", suite.name, suite.tests.len()), false);
 
-    let mut message_len = 0;
-    if let Ok(val) = tokens[2].parse::<usize>() {
-        message_len += val
+    let (labels, tests) = suite.expand().into_iter().unzip();
+    run_tests(config, tests, labels, Some(&output), format);
+}
+
+/// Loads the results saved in [path], exiting if they can't be.
+fn load_results(path: &str) -> Vec<TestData> {
+    import::load(path).unwrap_or_else(|e| {
+        pretty_print("ERR", "Import", &format!("'{}': {}", path, e), false);
+        process::exit(EXIT_USAGE)
+    })
+}
+
+/// Checks the results saved in [results] against the baseline in [baseline_file], exiting with
+/// [EXIT_REGRESSION] if any regressed.
+fn check_baseline(results: &str, baseline_file: &str, thresholds: &Thresholds) {
+    let baseline = Baseline::load(baseline_file).unwrap_or_else(|e| {
+        pretty_print("ERR", "Baseline", &format!("Failed to read baseline '{}', encountered error '{}'", baseline_file, e), false);
+        process::exit(EXIT_USAGE)
+    });
+    let current = load_results(results);
+    pretty_print("LOG", "Baseline", &format!("Checking '{}' against '{}' (saved from '{}')", results, baseline_file, baseline.source), false);
+
+    let report = baseline::check(&baseline.tests, &current, thresholds);
+    print!("{}", report);
+    let regressions = report.regressions();
+    if regressions.is_empty() {
+        pretty_print("LOG", "Baseline", "No regressions", false);
     } else {
-        pretty_print("ERR", "Program Argument", &format!("'{}' is not a valid number.", tokens[2]), false);
-        continue
+        pretty_print("ERR", "Baseline", &format!("{} regression(s): {}", regressions.len(), regressions.join(", ")), false);
+        process::exit(EXIT_REGRESSION)
     }
+}
 
-    if &tokens[0] == "udp" {
-            tests.push(test::Test::UdpTest(test::TestSpec {
-                num_messages,
-                message_len,
-            }));
-        } else if &tokens[0] == "tcp" {
-            tests.push(test::Test::TcpTest(test::TestSpec {
-                num_messages,
-                message_len,
-            }));
-        } else {
-            pretty_print("ERR", "Program Argument", &format!("'{}' is not a valid connection type (tcp or udp only).", tokens[1]), false);
+fn main() {
+    let matches = cli::app().get_matches_safe().unwrap_or_else(|e| {
+        match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(EXIT_USAGE)
+            }
         }
-    }
+    });
 
-    let _ = server.run_tests(tests);
+    util::set_verbosity(cli::verbosity(&matches));
 
-    //println!("{:?}", result);
-
-    println!("Successfully created server")
-}
-
-fn required() {
-    let mut server;
-    match server::Server::new() {
-        Ok(s) => server = s,
+    let config = match cli::config(&matches) {
+        Ok(config) => config,
         Err(e) => {
-            println!("Encountered error '{}' while trying to create server.", e);
-            return
+            pretty_print("ERR", "Config", &e.to_string(), false);
+            process::exit(EXIT_USAGE)
         }
     };
 
-    let result: Vec<test::TestData> = server.run_tests(vec![
-        Test::TcpTest(TestSpec { message_len: 1, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 64, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024, num_messages: 64 }),
-        Test::UdpTest(TestSpec { message_len: 1, num_messages: 64 }),
-        Test::UdpTest(TestSpec { message_len: 64, num_messages: 64 }),
-        Test::UdpTest(TestSpec { message_len: 1024, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024 * 16, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024 * 64, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024 * 256, num_messages: 64 }),
-        Test::TcpTest(TestSpec { message_len: 1024 * 1024, num_messages: 64 }),
-	Test::TcpTest(TestSpec { message_len: 1024 * 4, num_messages: 256 }),
-	Test::TcpTest(TestSpec { message_len: 1024 * 2, num_messages: 512 }),
-	Test::TcpTest(TestSpec { message_len: 1024 * 1, num_messages: 1024 }),
-	Test::UdpTest(TestSpec { message_len: 1024 * 4, num_messages: 256 }),
-	Test::UdpTest(TestSpec { message_len: 1024 * 2, num_messages: 512 }),
-	Test::UdpTest(TestSpec { message_len: 1024 * 1, num_messages: 1024 }),
-    ]).unwrap().into_iter().map(Result::ok).filter_map(|x| x).collect();
-
-    util::save_data_as_csv(result, "data.csv").unwrap();
-}
-
-
-
-fn main() {
-    let args = env::args().into_iter().collect::<Vec<String>>();
-
-    if args.len() == 1 {
-        println!("{}", USAGE_MESSAGE);
-    } else if &args[1] == ECHO {
-        echo::start_echo_server().unwrap();
-    } else if &args[1] == TEST {
-        test(args);
-    } else if &args[1] == REQ_DATA {
-        required();
-    } else {
-        println!("{}", USAGE_MESSAGE);
+    match matches.subcommand() {
+        (cli::ECHO, Some(sub)) => {
+            if let Err(e) = echo::start_echo_server(&config, &cli::echo_options(sub)) {
+                pretty_print("ERR", "Echo Server", &format!("Echo server encountered error '{}'", e), false);
+                process::exit(EXIT_FAILURE)
+            }
+        },
+        (cli::TEST, Some(sub)) => {
+            let tests = cli::tests(sub).unwrap_or_else(|e| {
+                pretty_print("ERR", "Program Argument", &e, false);
+                process::exit(EXIT_USAGE)
+            });
+            run_tests(&config, tests, vec![], sub.value_of("output"), cli::format(sub));
+        },
+        (cli::REQ_DATA, Some(sub)) => {
+            let suite = Suite::from_toml(suite::REQUIRED_SUITE).expect("the required suite is valid");
+            let format = cli::format(sub);
+            let output = sub.value_of("output").map(str::to_string)
+                .unwrap_or_else(|| format!("{}.{}", cli::DEFAULT_OUTPUT_NAME, format.extension()));
+            run_suite(&config, suite, Some(&output), format);
+        },
+        (cli::RUN, Some(sub)) => {
+            let path = sub.value_of("suite").unwrap();
+            let suite = Suite::load(path).unwrap_or_else(|e| {
+                pretty_print("ERR", "Suite", &format!("'{}': {}", path, e), false);
+                process::exit(EXIT_USAGE)
+            });
+            run_suite(&config, suite, sub.value_of("output"), cli::format(sub));
+        },
+        (cli::CONFIG, Some(sub)) => {
+            if let (cli::CONFIG_SHOW, Some(_)) = sub.subcommand() {
+                config.show();
+            }
+        },
+        (cli::COMPARE, Some(sub)) => {
+            let files: Vec<(String, Vec<TestData>)> = sub.values_of("files").unwrap()
+                .map(|path| (path.to_string(), load_results(path)))
+                .collect();
+            print!("{}", compare::Comparison::new(&files));
+        },
+        (cli::BASELINE, Some(sub)) => match sub.subcommand() {
+            (cli::BASELINE_SAVE, Some(save)) => {
+                let results = save.value_of("results").unwrap();
+                let baseline_file = save.value_of("baseline").unwrap();
+                let baseline = Baseline::new(results, load_results(results));
+                if let Err(e) = baseline.save(baseline_file) {
+                    pretty_print("ERR", "Baseline", &format!("Failed to write baseline to '{}', encountered error '{}'", baseline_file, e), false);
+                    process::exit(EXIT_FAILURE)
+                }
+                pretty_print("LOG", "Baseline", &format!("Saved {} tests from '{}' to '{}'", baseline.tests.len(), results, baseline_file), false);
+            },
+            (cli::BASELINE_CHECK, Some(check)) => {
+                let thresholds = cli::thresholds(check).unwrap_or_else(|e| {
+                    pretty_print("ERR", "Program Argument", &e, false);
+                    process::exit(EXIT_USAGE)
+                });
+                check_baseline(check.value_of("results").unwrap(), check.value_of("baseline").unwrap(), &thresholds);
+            },
+            _ => unreachable!("clap requires a subcommand"),
+        },
+        _ => unreachable!("clap requires a subcommand"),
     }
 }
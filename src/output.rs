@@ -0,0 +1,752 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::hash::{ Hash, Hasher };
+use std::io::{ self, Write };
+use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use csv::Writer;
+use serde::Serialize;
+use serde_json;
+
+use test::*;
+use config::Config;
+use error::{ DroppedMessage, FailureCause, TestError };
+use stats::LatencyStats;
+use util::pretty_print;
+
+/// The format results are saved in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// A summary file, with the samples, intervals and histograms in files next to it
+    #[default]
+    Csv,
+    /// One document with everything about the run
+    Json,
+    /// A record for every message as soon as it completes, and for every test once it finishes
+    Jsonl,
+}
+
+impl Format {
+    /// The extension of files in this format.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            Format::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!("'{}' is not a valid format (csv, json or jsonl only).", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// The version of the layout of the CSV files, which is the first column of every row so scripts
+/// reading them can tell when it changes. The single file with two tables that came before these
+/// is version 1.
+pub const CSV_SCHEMA_VERSION: u32 = 2;
+
+/// Identifies the results of one run of the program, so the rows of different runs can be told
+/// apart once they're put together.
+pub fn new_run_id() -> String {
+    let mut s = DefaultHasher::new();
+    SystemTime::now().hash(&mut s);
+    process::id().hash(&mut s);
+    format!("{:016x}", s.finish())
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+pub fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+/// What was ran, when, and against which echo server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunMetadata {
+    pub run_id: String,
+    /// The version of this program
+    pub version: String,
+    pub command_line: Vec<String>,
+    pub started_unix_ms: u64,
+    /// None until the run is over
+    pub finished_unix_ms: Option<u64>,
+    pub echo_server_tcp: SocketAddr,
+    pub echo_server_udp: SocketAddr,
+}
+
+impl RunMetadata {
+    /// Starts a new run, against the echo server in [config].
+    pub fn start(config: &Config) -> RunMetadata {
+        RunMetadata {
+            run_id: new_run_id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_line: env::args().collect(),
+            started_unix_ms: unix_ms(SystemTime::now()),
+            finished_unix_ms: None,
+            echo_server_tcp: config.echo_server_tcp_ip,
+            echo_server_udp: config.echo_server_udp_ip,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_unix_ms = Some(unix_ms(SystemTime::now()));
+    }
+}
+
+/// Everything about one test of a run: its spec, the statistics every format reports, and either
+/// its full results or why it failed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestRecord {
+    /// Which test of the run this is, counting from 0
+    pub index: usize,
+    pub label: Option<String>,
+    pub test: Test,
+    pub summary: Option<Summary>,
+    pub latency: Option<LatencyStats>,
+    /// Left out of the records of a JSON Lines log, which has a record for every message instead
+    pub data: Option<TestData>,
+    pub error: Option<String>,
+}
+
+impl TestRecord {
+    pub fn new(index: usize, label: Option<String>, test: Test, result: TestResult) -> TestRecord {
+        match result {
+            Ok(data) => TestRecord {
+                index,
+                label,
+                test,
+                summary: Some(data.summary()),
+                latency: data.latency_stats(),
+                data: Some(data),
+                error: None,
+            },
+            Err(e) => TestRecord { index, label, test, summary: None, latency: None, data: None, error: Some(e.to_string()) },
+        }
+    }
+}
+
+/// The JSON document of a whole run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunDocument {
+    pub metadata: RunMetadata,
+    pub tests: Vec<TestRecord>,
+}
+
+/// A row of the summary file: the overall results of a test, or of one stream of a parallel test.
+/// Times are in nanoseconds, and columns that don't apply to a test are empty.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SummaryRow {
+    pub schema_version: u32,
+    pub run_id: String,
+    /// Which test of the run this is, counting from 0
+    pub test_index: usize,
+    pub test_label: Option<String>,
+    pub protocol: Protocol,
+    pub direction: Direction,
+    /// Which stream of a parallel test this is, counting from 1. Empty for the whole test.
+    pub stream: Option<usize>,
+    pub message_len: usize,
+    pub messages_sent: usize,
+    pub messages_echoed: usize,
+    pub total_time_ns: u64,
+    pub mean_rtt_ns: Option<u64>,
+    pub min_rtt_ns: Option<u64>,
+    pub median_rtt_ns: Option<u64>,
+    pub p90_rtt_ns: Option<u64>,
+    pub p99_rtt_ns: Option<u64>,
+    pub p999_rtt_ns: Option<u64>,
+    pub max_rtt_ns: Option<u64>,
+    pub rtt_stddev_ns: Option<u64>,
+    pub jitter_ns: Option<u64>,
+    pub bytes_echoed: u64,
+    pub throughput_bytes_per_sec: Option<f64>,
+    pub dropped: usize,
+    pub dropped_timeout: usize,
+    pub dropped_io: usize,
+    pub dropped_wrong_peer: usize,
+    pub dropped_payload_mismatch: usize,
+    pub dropped_short_read: usize,
+    pub late_datagrams: usize,
+    pub reordered_datagrams: usize,
+    pub duplicated_datagrams: usize,
+    pub lost_datagrams: usize,
+    pub loss_percent: f64,
+    pub target_bps: Option<f64>,
+    pub achieved_bps: Option<f64>,
+    pub goodput_bps: Option<f64>,
+    pub sent_goodput_bps: Option<f64>,
+    pub server_bytes_received: Option<u64>,
+    pub server_bytes_sent: Option<u64>,
+    pub server_datagrams_received: Option<u64>,
+    pub server_datagrams_echoed: Option<u64>,
+    pub mean_connect_ns: Option<u64>,
+    pub mean_first_byte_ns: Option<u64>,
+    pub mean_close_ns: Option<u64>,
+    /// Why the test failed. The other columns of a test that failed are empty or 0.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl SummaryRow {
+    fn new(run_id: &str, test_index: usize, test: &TestData, label: Option<String>, stream: Option<usize>) -> SummaryRow {
+        let summary = test.summary();
+        let latency = test.latency_stats();
+        let latency_ns = |statistic: fn(&LatencyStats) -> Duration| latency.as_ref().map(|latency| nanos(statistic(latency)));
+        let connections = test.average_connection_times();
+        let server = test.server_stats.as_ref();
+        SummaryRow {
+            schema_version: CSV_SCHEMA_VERSION,
+            run_id: run_id.to_string(),
+            test_index,
+            test_label: label,
+            protocol: test.test.protocol(),
+            direction: test.test.spec().direction,
+            stream,
+            message_len: test.test.spec().message_len,
+            messages_sent: summary.messages,
+            messages_echoed: summary.successful,
+            total_time_ns: nanos(summary.wall_clock),
+            mean_rtt_ns: summary.mean_rtt.map(nanos),
+            min_rtt_ns: latency_ns(|latency| latency.min),
+            median_rtt_ns: latency_ns(|latency| latency.median),
+            p90_rtt_ns: latency_ns(|latency| latency.p90),
+            p99_rtt_ns: latency_ns(|latency| latency.p99),
+            p999_rtt_ns: latency_ns(|latency| latency.p999),
+            max_rtt_ns: latency_ns(|latency| latency.max),
+            rtt_stddev_ns: latency_ns(|latency| latency.stddev),
+            jitter_ns: test.jitter().map(nanos),
+            bytes_echoed: summary.bytes_echoed,
+            throughput_bytes_per_sec: summary.throughput,
+            dropped: test.dropped_messages.len(),
+            dropped_timeout: test.dropped_because(FailureCause::Timeout),
+            dropped_io: test.dropped_because(FailureCause::Io),
+            dropped_wrong_peer: test.dropped_because(FailureCause::WrongPeer),
+            dropped_payload_mismatch: test.dropped_because(FailureCause::PayloadMismatch),
+            dropped_short_read: test.dropped_because(FailureCause::ShortRead),
+            late_datagrams: test.datagrams.late.len(),
+            reordered_datagrams: test.datagrams.reordered.len(),
+            duplicated_datagrams: test.datagrams.duplicated.len(),
+            lost_datagrams: test.datagrams.lost.len(),
+            loss_percent: test.loss_percent(),
+            target_bps: test.target_bps(),
+            achieved_bps: test.achieved_bps(),
+            goodput_bps: test.throughput.as_ref().map(Throughput::goodput_bps),
+            sent_goodput_bps: test.sent_throughput.as_ref().map(Throughput::goodput_bps),
+            server_bytes_received: server.map(|stats| stats.tcp_bytes_received),
+            server_bytes_sent: server.map(|stats| stats.tcp_bytes_sent),
+            server_datagrams_received: server.map(|stats| stats.datagrams_received),
+            server_datagrams_echoed: server.map(|stats| stats.datagrams_echoed),
+            mean_connect_ns: connections.map(|times| nanos(times.connect)),
+            mean_first_byte_ns: connections.map(|times| nanos(times.first_byte)),
+            mean_close_ns: connections.map(|times| nanos(times.close)),
+            error: None,
+        }
+    }
+
+    /// The row of a test that failed, which only has its spec and why it failed.
+    fn failed(run_id: &str, record: &TestRecord) -> SummaryRow {
+        SummaryRow {
+            schema_version: CSV_SCHEMA_VERSION,
+            run_id: run_id.to_string(),
+            test_index: record.index,
+            test_label: record.label.clone(),
+            protocol: record.test.protocol(),
+            direction: record.test.spec().direction,
+            stream: None,
+            message_len: record.test.spec().message_len,
+            messages_sent: 0,
+            messages_echoed: 0,
+            total_time_ns: 0,
+            mean_rtt_ns: None,
+            min_rtt_ns: None,
+            median_rtt_ns: None,
+            p90_rtt_ns: None,
+            p99_rtt_ns: None,
+            p999_rtt_ns: None,
+            max_rtt_ns: None,
+            rtt_stddev_ns: None,
+            jitter_ns: None,
+            bytes_echoed: 0,
+            throughput_bytes_per_sec: None,
+            dropped: 0,
+            dropped_timeout: 0,
+            dropped_io: 0,
+            dropped_wrong_peer: 0,
+            dropped_payload_mismatch: 0,
+            dropped_short_read: 0,
+            late_datagrams: 0,
+            reordered_datagrams: 0,
+            duplicated_datagrams: 0,
+            lost_datagrams: 0,
+            loss_percent: 0.0,
+            target_bps: None,
+            achieved_bps: None,
+            goodput_bps: None,
+            sent_goodput_bps: None,
+            server_bytes_received: None,
+            server_bytes_sent: None,
+            server_datagrams_received: None,
+            server_datagrams_echoed: None,
+            mean_connect_ns: None,
+            mean_first_byte_ns: None,
+            mean_close_ns: None,
+            error: record.error.clone(),
+        }
+    }
+}
+
+/// Whether a message was echoed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleStatus {
+    Echoed,
+    Dropped,
+}
+
+/// A row of the samples file: one message of a test.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SampleRow {
+    pub schema_version: u32,
+    pub run_id: String,
+    pub test_index: usize,
+    pub test_label: Option<String>,
+    pub protocol: Protocol,
+    /// Which stream of a parallel test sent the message, counting from 1. Empty for other tests.
+    pub stream: Option<usize>,
+    /// The number of the message within the whole test, across every stream
+    pub message_index: u32,
+    pub message_len: usize,
+    /// Empty for messages that were dropped
+    pub rtt_ns: Option<u64>,
+    pub status: SampleStatus,
+    pub failure_cause: Option<FailureCause>,
+    pub failure_reason: Option<String>,
+    /// Only tests that open a connection for every message have these
+    pub connect_ns: Option<u64>,
+    pub first_byte_ns: Option<u64>,
+    pub close_ns: Option<u64>,
+}
+
+/// A row of the intervals file: how much data a bulk test received (or sent) during one interval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntervalRow {
+    pub schema_version: u32,
+    pub run_id: String,
+    pub test_index: usize,
+    pub test_label: Option<String>,
+    pub protocol: Protocol,
+    pub direction: Direction,
+    pub stream: Option<usize>,
+    /// Whether this is data the client received, or data a bidirectional test sent
+    pub sent: bool,
+    pub interval_index: usize,
+    pub interval_ns: u64,
+    pub bytes: u64,
+    pub bps: f64,
+}
+
+/// A row of the histogram file: how many round trip times of a test fell in one bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistogramRow {
+    pub schema_version: u32,
+    pub run_id: String,
+    pub test_index: usize,
+    pub test_label: Option<String>,
+    pub protocol: Protocol,
+    pub message_len: usize,
+    pub lower_ns: u64,
+    pub upper_ns: u64,
+    pub count: usize,
+}
+
+impl SampleRow {
+    /// What the rows of every message of [test] have in common.
+    fn template(run_id: &str, test_index: usize, test_label: Option<String>, test: &Test) -> SampleRow {
+        SampleRow {
+            schema_version: CSV_SCHEMA_VERSION,
+            run_id: run_id.to_string(),
+            test_index,
+            test_label,
+            protocol: test.protocol(),
+            stream: None,
+            message_index: 0,
+            message_len: test.spec().message_len,
+            rtt_ns: None,
+            status: SampleStatus::Dropped,
+            failure_cause: None,
+            failure_reason: None,
+            connect_ns: None,
+            first_byte_ns: None,
+            close_ns: None,
+        }
+    }
+
+    /// The row of message [message_index], which ended with [result].
+    fn with_result(&self, message_index: u32, result: &Result<Duration, TestError>, connection: Option<&ConnectionTimes>) -> SampleRow {
+        let error = result.as_ref().err();
+        SampleRow {
+            message_index,
+            rtt_ns: result.as_ref().ok().map(|rtt| nanos(*rtt)),
+            status: if result.is_ok() { SampleStatus::Echoed } else { SampleStatus::Dropped },
+            failure_cause: error.map(TestError::cause),
+            failure_reason: error.map(TestError::to_string),
+            connect_ns: connection.map(|times| nanos(times.connect)),
+            first_byte_ns: connection.map(|times| nanos(times.first_byte)),
+            close_ns: connection.map(|times| nanos(times.close)),
+            ..self.clone()
+        }
+    }
+}
+
+/// The tests [test] was made of: the test itself if it had a single stream, or each of its
+/// streams, along with the stream number and the number of its first message within the test.
+fn streams(test: &TestData) -> Vec<(&TestData, Option<usize>, u32)> {
+    if test.streams.is_empty() {
+        return vec![(test, None, 0)]
+    }
+    let mut offset = 0;
+    test.streams.iter().enumerate().map(|(i, stream)| {
+        let first = offset;
+        offset += stream.individual_durations.len() as u32;
+        (stream, Some(i + 1), first)
+    }).collect()
+}
+
+fn samples(run_id: &str, test_index: usize, test: &TestData) -> Vec<SampleRow> {
+    let mut rows = vec![];
+    for (stream_data, stream, first) in streams(test) {
+        let dropped_messages: HashMap<u32, &DroppedMessage> = stream_data.dropped_messages.iter()
+            .map(|dropped| (dropped.message_number, dropped))
+            .collect();
+        for (i, duration) in stream_data.individual_durations.iter().enumerate() {
+            let dropped = dropped_messages.get(&(i as u32));
+            let connection = stream_data.connections.get(i).cloned().flatten();
+            rows.push(SampleRow {
+                schema_version: CSV_SCHEMA_VERSION,
+                run_id: run_id.to_string(),
+                test_index,
+                test_label: test.label.clone(),
+                protocol: test.test.protocol(),
+                stream,
+                message_index: first + i as u32,
+                message_len: test.test.spec().message_len,
+                rtt_ns: duration.map(nanos),
+                status: if duration.is_some() { SampleStatus::Echoed } else { SampleStatus::Dropped },
+                failure_cause: dropped.map(|dropped| dropped.cause),
+                failure_reason: dropped.map(|dropped| dropped.detail.clone()),
+                connect_ns: connection.map(|times| nanos(times.connect)),
+                first_byte_ns: connection.map(|times| nanos(times.first_byte)),
+                close_ns: connection.map(|times| nanos(times.close)),
+            });
+        }
+    }
+    rows
+}
+
+fn intervals(run_id: &str, test_index: usize, test: &TestData) -> Vec<IntervalRow> {
+    let mut rows = vec![];
+    for (stream_data, stream, _) in streams(test) {
+        let directions = stream_data.throughput.iter().map(|throughput| (throughput, false))
+            .chain(stream_data.sent_throughput.iter().map(|throughput| (throughput, true)));
+        for (throughput, sent) in directions {
+            let samples = throughput.interval_bytes.iter().zip(throughput.interval_lengths()).zip(throughput.interval_bps());
+            for (interval_index, ((bytes, length), bps)) in samples.enumerate() {
+                rows.push(IntervalRow {
+                    schema_version: CSV_SCHEMA_VERSION,
+                    run_id: run_id.to_string(),
+                    test_index,
+                    test_label: test.label.clone(),
+                    protocol: test.test.protocol(),
+                    direction: test.test.spec().direction,
+                    stream,
+                    sent,
+                    interval_index,
+                    interval_ns: nanos(length),
+                    bytes: *bytes,
+                    bps,
+                });
+            }
+        }
+    }
+    rows
+}
+
+fn histogram(run_id: &str, test_index: usize, test: &TestData) -> Vec<HistogramRow> {
+    test.histogram().buckets.into_iter().map(|bucket| HistogramRow {
+        schema_version: CSV_SCHEMA_VERSION,
+        run_id: run_id.to_string(),
+        test_index,
+        test_label: test.label.clone(),
+        protocol: test.test.protocol(),
+        message_len: test.test.spec().message_len,
+        lower_ns: nanos(bucket.lower),
+        upper_ns: nanos(bucket.upper),
+        count: bucket.count,
+    }).collect()
+}
+
+/// The file next to [filename] that has the rows of [kind], so `results.csv` has its samples in
+/// `results.samples.csv`.
+pub fn companion_file(filename: &str, kind: &str) -> String {
+    let stem = filename.strip_suffix(".csv").unwrap_or(filename);
+    format!("{}.{}.csv", stem, kind)
+}
+
+fn write_rows<T: Serialize>(filename: &str, rows: &[T]) -> Result<(), io::Error> {
+    let mut writer = Writer::from_writer(File::create(filename)?);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()
+}
+
+/// Saves the tests in [records] from run [run_id] as CSV: a row per test (and per stream of a
+/// parallel test) in [filename], and a row per message, per interval of a bulk test and per
+/// histogram bucket in files next to it, if there are any. A test that failed only has a row in
+/// [filename], saying why. Returns every file written after [filename].
+pub fn save_csv(records: &[TestRecord], run_id: &str, filename: &str) -> Result<Vec<String>, io::Error> {
+    let mut summaries = vec![];
+    let (mut sample_rows, mut interval_rows, mut histogram_rows) = (vec![], vec![], vec![]);
+    for record in records {
+        let (test_index, test) = match record.data {
+            Some(ref test) => (record.index, test),
+            None => {
+                summaries.push(SummaryRow::failed(run_id, record));
+                continue
+            },
+        };
+        summaries.push(SummaryRow::new(run_id, test_index, test, test.label.clone(), None));
+        for (i, stream) in test.streams.iter().enumerate() {
+            summaries.push(SummaryRow::new(run_id, test_index, stream, test.label.clone(), Some(i + 1)));
+        }
+        sample_rows.extend(samples(run_id, test_index, test));
+        interval_rows.extend(intervals(run_id, test_index, test));
+        histogram_rows.extend(histogram(run_id, test_index, test));
+    }
+
+    write_rows(filename, &summaries)?;
+    let mut written = vec![];
+    if !sample_rows.is_empty() {
+        written.push(companion_file(filename, "samples"));
+        write_rows(&written[written.len() - 1], &sample_rows)?;
+    }
+    if !interval_rows.is_empty() {
+        written.push(companion_file(filename, "intervals"));
+        write_rows(&written[written.len() - 1], &interval_rows)?;
+    }
+    if !histogram_rows.is_empty() {
+        written.push(companion_file(filename, "histogram"));
+        write_rows(&written[written.len() - 1], &histogram_rows)?;
+    }
+    Ok(written)
+}
+
+/// Saves [document] as pretty printed JSON.
+pub fn save_json(document: &RunDocument, filename: &str) -> Result<(), io::Error> {
+    let mut file = File::create(filename)?;
+    serde_json::to_writer_pretty(&mut file, document)?;
+    file.write_all(b"\n")
+}
+
+/// A line of a JSON Lines log, tagged with its type.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogRecord {
+    /// The first record, before any test is ran
+    Run(RunMetadata),
+    /// A message of a test, as soon as it has been echoed or given up on
+    Message(SampleRow),
+    /// A test, once it has finished
+    Test(Box<TestRecord>),
+    /// The last record, once every test has finished
+    End { run_id: String, finished_unix_ms: u64 },
+}
+
+/// Writes a JSON Lines log of a run while it's going, so it can be followed and ingested before
+/// the run is over. Each record is written with a single write as soon as it's known.
+pub struct RunLog {
+    run_id: String,
+    labels: Vec<String>,
+    file: Mutex<File>,
+    /// Whether writing a record has failed, so the failure is only reported once
+    failed: AtomicBool,
+}
+
+impl RunLog {
+    /// Creates the log at [filename] and writes the record of the run to it. [labels] are the
+    /// labels of the tests, in order, if they have them.
+    pub fn create(filename: &str, metadata: &RunMetadata, labels: Vec<String>) -> Result<RunLog, io::Error> {
+        let log = RunLog { run_id: metadata.run_id.clone(), labels, file: Mutex::new(File::create(filename)?), failed: AtomicBool::new(false) };
+        log.try_write(&LogRecord::Run(metadata.clone()))?;
+        Ok(log)
+    }
+
+    fn try_write(&self, record: &LogRecord) -> Result<(), io::Error> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+
+    fn write(&self, record: &LogRecord) {
+        if let Err(e) = self.try_write(record) {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                pretty_print("ERR", "Output", &format!("Failed to write to the JSON Lines log, encountered error '{}'", e), false);
+            }
+        }
+    }
+
+    fn label(&self, test_index: usize) -> Option<String> {
+        self.labels.get(test_index).cloned()
+    }
+
+    /// Where the messages of [test], the [test_index]th test of the run, are logged.
+    pub fn messages(log: &Arc<RunLog>, test_index: usize, test: &Test) -> MessageLog {
+        MessageLog { log: log.clone(), row: SampleRow::template(&log.run_id, test_index, log.label(test_index), test), first: 0 }
+    }
+
+    /// Logs how the [test_index]th test of the run went, without its full results.
+    pub fn test_finished(&self, test_index: usize, test: &Test, result: &TestResult) {
+        let mut record = TestRecord {
+            index: test_index,
+            label: self.label(test_index),
+            test: test.clone(),
+            summary: None,
+            latency: None,
+            data: None,
+            error: None,
+        };
+        match *result {
+            Ok(ref data) => {
+                record.summary = Some(data.summary());
+                record.latency = data.latency_stats();
+            },
+            Err(ref e) => record.error = Some(e.to_string()),
+        }
+        self.write(&LogRecord::Test(Box::new(record)));
+    }
+
+    /// Logs that every test has finished.
+    pub fn finish(&self, metadata: &RunMetadata) {
+        let finished_unix_ms = metadata.finished_unix_ms.unwrap_or_else(|| unix_ms(SystemTime::now()));
+        self.write(&LogRecord::End { run_id: self.run_id.clone(), finished_unix_ms });
+    }
+}
+
+/// Logs each message of one test (or one stream of a parallel test) to a [RunLog].
+#[derive(Clone)]
+pub struct MessageLog {
+    log: Arc<RunLog>,
+    /// What every record of this test has in common
+    row: SampleRow,
+    /// The number of the first message of this stream within the whole test
+    first: u32,
+}
+
+impl MessageLog {
+    /// Where the messages of stream [stream] (counting from 1) of a parallel test are logged. Every
+    /// stream sends [num_messages], and they're numbered as if the streams sent them one after
+    /// another, like [TestData::combine] does.
+    pub fn for_stream(&self, stream: usize, num_messages: u32) -> MessageLog {
+        MessageLog {
+            log: self.log.clone(),
+            row: SampleRow { stream: Some(stream), ..self.row.clone() },
+            first: (stream as u32 - 1) * num_messages,
+        }
+    }
+
+    /// Logs how message [message_number] of this stream went.
+    pub fn record(&self, message_number: u32, result: &Result<Duration, TestError>, connection: Option<&ConnectionTimes>) {
+        self.log.write(&LogRecord::Message(self.row.with_result(self.first + message_number, result, connection)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A TCP test of 3 messages, the second of which timed out.
+    fn tcp_test() -> TestData {
+        let durations = vec![Some(Duration::from_micros(1500)), None, Some(Duration::from_micros(2500))];
+        let spec = TestSpec { num_messages: 3, message_len: 64, ..TestSpec::default() };
+        TestData::new(Test::TcpTest(spec), Duration::from_millis(10), durations, vec![DroppedMessage::new(1, &TestError::Timeout)])
+    }
+
+    /// Saves a run with a test that succeeded and one that failed, and returns the lines of the
+    /// summary, samples and histogram files.
+    fn saved_csv(name: &str) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let filename = env::temp_dir().join(format!("dl1-output-{}-{}.csv", process::id(), name)).to_string_lossy().into_owned();
+        let test = tcp_test();
+        let records = [
+            TestRecord::new(0, Some("ok".to_string()), test.test.clone(), Ok(TestData { label: Some("ok".to_string()), ..tcp_test() })),
+            TestRecord::new(1, Some("failed".to_string()), test.test.clone(), Err(TestError::Timeout)),
+        ];
+        let written = save_csv(&records, "run", &filename).unwrap();
+        assert_eq!(written, vec![companion_file(&filename, "samples"), companion_file(&filename, "histogram")]);
+        let lines = |file: &str| fs::read_to_string(file).unwrap().lines().map(str::to_string).collect::<Vec<_>>();
+        let files = (lines(&filename), lines(&written[0]), lines(&written[1]));
+        for file in written.iter().chain(Some(&filename)) {
+            let _ = fs::remove_file(file);
+        }
+        files
+    }
+
+    // Scripts and `import` read these files by column name, so columns can only be added at the
+    // end, and only along with a new schema version
+    #[test]
+    fn csv_headers_match_schema_version_2() {
+        assert_eq!(CSV_SCHEMA_VERSION, 2);
+        let (summary, samples, histogram) = saved_csv("headers");
+        assert_eq!(summary[0], "schema_version,run_id,test_index,test_label,protocol,direction,stream,message_len,messages_sent,\
+messages_echoed,total_time_ns,mean_rtt_ns,min_rtt_ns,median_rtt_ns,p90_rtt_ns,p99_rtt_ns,p999_rtt_ns,max_rtt_ns,rtt_stddev_ns,\
+jitter_ns,bytes_echoed,throughput_bytes_per_sec,dropped,dropped_timeout,dropped_io,dropped_wrong_peer,dropped_payload_mismatch,\
+dropped_short_read,late_datagrams,reordered_datagrams,duplicated_datagrams,lost_datagrams,loss_percent,target_bps,achieved_bps,\
+goodput_bps,sent_goodput_bps,server_bytes_received,server_bytes_sent,server_datagrams_received,server_datagrams_echoed,\
+mean_connect_ns,mean_first_byte_ns,mean_close_ns,error");
+        assert_eq!(samples[0], "schema_version,run_id,test_index,test_label,protocol,stream,message_index,message_len,rtt_ns,status,\
+failure_cause,failure_reason,connect_ns,first_byte_ns,close_ns");
+        assert_eq!(histogram[0], "schema_version,run_id,test_index,test_label,protocol,message_len,lower_ns,upper_ns,count");
+    }
+
+    #[test]
+    fn csv_rows_of_a_run() {
+        let (summary, samples, histogram) = saved_csv("rows");
+        assert_eq!(summary.len(), 3);
+        assert!(summary[1].starts_with("2,run,0,ok,tcp,echo,,64,3,2,10000000,2000000,"), "{}", summary[1]);
+        // A test that failed has a row of zeros and empty columns saying why
+        assert_eq!(summary[2], "2,run,1,failed,tcp,echo,,64,0,0,0,,,,,,,,,,0,,0,0,0,0,0,0,0,0,0,0,0.0,,,,,,,,,,,,timed out");
+
+        assert_eq!(&samples[1..], [
+            "2,run,0,ok,tcp,,0,64,1500000,echoed,,,,,",
+            "2,run,0,ok,tcp,,1,64,,dropped,timeout,timed out,,,",
+            "2,run,0,ok,tcp,,2,64,2500000,echoed,,,,,",
+        ]);
+        assert_eq!(&histogram[1..], ["2,run,0,ok,tcp,64,1048576,2097152,1", "2,run,0,ok,tcp,64,2097152,4194304,1"]);
+    }
+}
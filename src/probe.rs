@@ -0,0 +1,219 @@
+use std::time::{ Duration, Instant };
+
+use error::TestError;
+use test::DatagramClasses;
+
+/// The length of the header at the start of every UDP probe. Messages shorter than this are
+/// padded to this length, since the header has to fit, and are reported as being this long.
+pub const PROBE_HEADER_LEN: usize = 16;
+
+/// The header at the start of every datagram sent by a UDP test, so echoes can be matched to the
+/// message they're an echo of no matter when they arrive. Every field is big endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeHeader {
+    /// Identifies the test the datagram was sent by, so echoes of an earlier test are ignored
+    pub test_id: u32,
+    /// The message number
+    pub seq: u32,
+    /// When the datagram was sent, in nanoseconds since the test started
+    pub send_time_ns: u64,
+}
+
+impl ProbeHeader {
+    /// Writes the header to the start of [message], and fills the rest of it with the sequence
+    /// number, repeated.
+    pub fn fill(&self, message: &mut [u8]) {
+        message[0..4].copy_from_slice(&self.test_id.to_be_bytes());
+        message[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        message[8..16].copy_from_slice(&self.send_time_ns.to_be_bytes());
+        let seq_bytes = self.seq.to_ne_bytes();
+        for p in PROBE_HEADER_LEN..message.len() {
+            message[p] = seq_bytes[p & 3];
+        }
+    }
+
+    /// Reads the header from the start of [datagram], if it is long enough to have one.
+    pub fn read(datagram: &[u8]) -> Option<ProbeHeader> {
+        if datagram.len() < PROBE_HEADER_LEN {
+            return None
+        }
+        let mut test_id = [0u8; 4];
+        let mut seq = [0u8; 4];
+        let mut send_time_ns = [0u8; 8];
+        test_id.copy_from_slice(&datagram[0..4]);
+        seq.copy_from_slice(&datagram[4..8]);
+        send_time_ns.copy_from_slice(&datagram[8..16]);
+        Some(ProbeHeader {
+            test_id: u32::from_be_bytes(test_id),
+            seq: u32::from_be_bytes(seq),
+            send_time_ns: u64::from_be_bytes(send_time_ns),
+        })
+    }
+
+    /// Checks that everything after the header is what [ProbeHeader::fill] wrote.
+    pub fn check_payload(&self, datagram: &[u8]) -> Result<(), TestError> {
+        let seq_bytes = self.seq.to_ne_bytes();
+        match (PROBE_HEADER_LEN..datagram.len()).find(|&p| datagram[p] != seq_bytes[p & 3]) {
+            Some(offset) => Err(TestError::PayloadMismatch { offset }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How an echoed probe arrived, relative to the other probes of the test.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arrival {
+    /// The first echo of the probe, within the timeout and after every probe sent before it
+    OnTime,
+    /// The first echo of the probe, but after the timeout, when it had already been given up on
+    Late,
+    /// The first echo of the probe, within the timeout, but after a probe that was sent later
+    Reordered,
+    /// The probe's echo had already been received
+    Duplicate,
+}
+
+/// An echo of one of the test's probes.
+#[derive(Debug, Clone, Copy)]
+pub struct Echo {
+    pub header: ProbeHeader,
+    pub arrival: Arrival,
+    /// How long after being sent the echo arrived
+    pub rtt: Duration,
+}
+
+/// Keeps track of every probe a UDP test sends and every echo it receives, classifying each echo
+/// as it arrives.
+pub struct ProbeTracker {
+    test_id: u32,
+    start: Instant,
+    timeout: Duration,
+    sent: Vec<bool>,
+    received: Vec<bool>,
+    highest_received: Option<u32>,
+    classes: DatagramClasses,
+}
+
+impl ProbeTracker {
+    pub fn new(test_id: u32, num_messages: u32, timeout: Duration) -> ProbeTracker {
+        ProbeTracker {
+            test_id,
+            start: Instant::now(),
+            timeout,
+            sent: vec![false; num_messages as usize],
+            received: vec![false; num_messages as usize],
+            highest_received: None,
+            classes: DatagramClasses::default(),
+        }
+    }
+
+    /// The header for message [seq], stamped with the current time. The message is assumed to be
+    /// sent right after this is called.
+    pub fn header(&mut self, seq: u32) -> ProbeHeader {
+        self.sent[seq as usize] = true;
+        ProbeHeader { test_id: self.test_id, seq, send_time_ns: self.start.elapsed().as_nanos() as u64 }
+    }
+
+    /// Classifies a datagram received at [now]. Returns None if it isn't an echo of one of this
+    /// test's probes, like a late echo from an earlier test.
+    pub fn receive(&mut self, datagram: &[u8], now: Instant) -> Option<Echo> {
+        let header = ProbeHeader::read(datagram)?;
+        let seq = header.seq as usize;
+        if header.test_id != self.test_id || seq >= self.sent.len() || !self.sent[seq] {
+            return None
+        }
+
+        let since_start = now.saturating_duration_since(self.start);
+        let rtt = since_start.checked_sub(Duration::from_nanos(header.send_time_ns)).unwrap_or_default();
+        let arrival = if self.received[seq] {
+            self.classes.duplicated.push(header.seq);
+            Arrival::Duplicate
+        } else if rtt > self.timeout {
+            self.classes.late.push(header.seq);
+            Arrival::Late
+        } else if self.highest_received.is_some_and(|highest| header.seq < highest) {
+            self.classes.reordered.push(header.seq);
+            Arrival::Reordered
+        } else {
+            Arrival::OnTime
+        };
+
+        self.received[seq] = true;
+        self.highest_received = self.highest_received.max(Some(header.seq));
+        Some(Echo { header, arrival, rtt })
+    }
+
+    /// Every probe that was sent but never echoed is lost.
+    pub fn finish(mut self) -> DatagramClasses {
+        self.classes.lost = (0..self.sent.len())
+            .filter(|&seq| self.sent[seq] && !self.received[seq])
+            .map(|seq| seq as u32)
+            .collect();
+        self.classes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The datagram of [header], as it would be echoed.
+    fn datagram(header: ProbeHeader) -> Vec<u8> {
+        let mut datagram = vec![0u8; 32];
+        header.fill(&mut datagram);
+        datagram
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = ProbeHeader { test_id: 7, seq: 3, send_time_ns: 123_456_789 };
+        let datagram = datagram(header);
+        assert_eq!(ProbeHeader::read(&datagram), Some(header));
+        assert!(header.check_payload(&datagram).is_ok());
+        assert!(ProbeHeader::read(&datagram[..PROBE_HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn classifies_on_time_reordered_and_duplicate_echoes() {
+        let mut tracker = ProbeTracker::new(1, 4, Duration::from_secs(10));
+        let headers: Vec<ProbeHeader> = (0..3).map(|seq| tracker.header(seq)).collect();
+        let now = Instant::now();
+
+        assert_eq!(tracker.receive(&datagram(headers[1]), now).unwrap().arrival, Arrival::OnTime);
+        assert_eq!(tracker.receive(&datagram(headers[0]), now).unwrap().arrival, Arrival::Reordered);
+        assert_eq!(tracker.receive(&datagram(headers[0]), now).unwrap().arrival, Arrival::Duplicate);
+
+        let classes = tracker.finish();
+        assert_eq!(classes.reordered, vec![0]);
+        assert_eq!(classes.duplicated, vec![0]);
+        assert!(classes.late.is_empty());
+        // Message 3 was never sent, so it isn't lost
+        assert_eq!(classes.lost, vec![2]);
+    }
+
+    #[test]
+    fn classifies_echoes_after_the_timeout_as_late() {
+        let mut tracker = ProbeTracker::new(1, 2, Duration::from_millis(10));
+        let header = tracker.header(0);
+        let echo = tracker.receive(&datagram(header), Instant::now() + Duration::from_secs(1)).unwrap();
+        assert_eq!(echo.arrival, Arrival::Late);
+        assert!(echo.rtt > Duration::from_millis(10));
+
+        let classes = tracker.finish();
+        assert_eq!(classes.late, vec![0]);
+        assert!(classes.lost.is_empty());
+    }
+
+    #[test]
+    fn ignores_datagrams_that_arent_echoes_of_its_probes() {
+        let mut tracker = ProbeTracker::new(1, 2, Duration::from_secs(10));
+        let header = tracker.header(0);
+        let now = Instant::now();
+        assert!(tracker.receive(&datagram(ProbeHeader { test_id: 2, ..header }), now).is_none());
+        // Message 1 was never sent, and message 5 isn't part of the test
+        assert!(tracker.receive(&datagram(ProbeHeader { seq: 1, ..header }), now).is_none());
+        assert!(tracker.receive(&datagram(ProbeHeader { seq: 5, ..header }), now).is_none());
+        assert!(tracker.receive(&[0u8; 4], now).is_none());
+        assert_eq!(tracker.finish().lost, vec![0]);
+    }
+}
@@ -1,230 +1,508 @@
 use std::net::*;
 use std::io::{ Read, Write, self };
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::hint;
+use std::thread;
 use std::time::{ Duration, Instant };
 use std::hash::{ Hash, Hasher };
 use std::collections::hash_map::DefaultHasher;
-use std::mem;
 
 use test::*;
 use config::*;
-use util::*;
+use error::{ DroppedMessage, TestError };
+use probe::{ Arrival, ProbeTracker, PROBE_HEADER_LEN };
+use frame::{ FrameHeader, FRAME_HEADER_LEN };
+use control::{ self, Allocation, ControlChannel, BULK_PATTERN_LEN, bulk_pattern };
+use output::{ MessageLog, RunLog };
+use util::pretty_print;
 
-#[allow(non_snake_case)]
-fn TIMEOUT_DURATION() -> Duration { Duration::new(10, 0) }
+/// TCP messages longer than this are written from another thread while the echo is read. The echo
+/// server stops reading once it can't send anything back, so writing all of a message this long
+/// before reading could fill the connection in both directions and never finish.
+const CONCURRENT_WRITE_LEN: usize = 64 * 1024;
+
+/// The longest a UDP test waits after its last message for the echoes of messages that timed out,
+/// so they're counted as late rather than lost.
+const LATE_ARRIVAL_GRACE: Duration = Duration::from_millis(250);
+
+/// How often a bulk TCP test samples its throughput if its spec doesn't say.
+const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much of the echo a bulk TCP test reads at once.
+const BULK_READ_LEN: usize = 64 * 1024;
+
+/// How long a bulk TCP test's reads wait before checking whether the writer is done.
+const BULK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sleeps are only used to wait for a paced send while it's further away than this, since they can
+/// overshoot; the rest of the wait is spent spinning.
+const SPIN_THRESHOLD: Duration = Duration::from_micros(200);
+
+/// How long the receiver of a pipelined UDP test waits for an echo when no messages are in flight.
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Server {
     udp: UdpSocket,
     udp_dst: SocketAddr,
-    tcp: TcpStream,
+    /// Where the echo server accepts TCP connections
+    tcp_dst: SocketAddr,
+    /// The data connection of the TCP test currently being ran. Each TCP test opens its own.
+    tcp: Option<TcpStream>,
+    /// Bytes read from the data connection past the end of the last echo
+    tcp_pending: Vec<u8>,
+    /// The timeout used for tests that don't specify their own
+    default_timeout: Duration,
+    /// The timeout of the test currently being ran
+    timeout: Duration,
+    /// Where each message of the test currently being ran is logged as soon as it completes
+    messages: Option<MessageLog>,
 }
 
 impl Server {
-    pub fn new() -> Result<Self, io::Error> {
-        let udp = UdpSocket::bind(UDP_IP)?;
-        let udp_dst = create_address(ECHO_SERVER_UDP_IP).unwrap();
-        let tcp = TcpStream::connect(ECHO_SERVER_TCP_IP)?;
+    pub fn new(config: &Config) -> Result<Self, io::Error> {
+        let udp = UdpSocket::bind(config.udp_ip)?;
+        Server::from_socket(udp, config.echo_server_udp_ip, config.echo_server_tcp_ip, config.timeout)
+    }
+
+    fn from_socket(udp: UdpSocket, udp_dst: SocketAddr, tcp_dst: SocketAddr, timeout: Duration) -> Result<Self, io::Error> {
         udp.set_nonblocking(false)?;
-        tcp.set_nonblocking(false)?;
+        udp.set_read_timeout(Some(timeout))?;
+        Ok(Server { udp, udp_dst, tcp_dst, tcp: None, tcp_pending: Vec::new(), default_timeout: timeout, timeout, messages: None })
+    }
 
-        udp.set_read_timeout(Some(TIMEOUT_DURATION()))?;
-        tcp.set_read_timeout(Some(TIMEOUT_DURATION()))?;
+    /// Opens another UDP socket (on any port of the same address) to the same echo server, to run
+    /// one stream of a parallel test over. TCP streams open their own data connections.
+    fn open_stream(&self) -> Result<Server, TestError> {
+        let udp = UdpSocket::bind(SocketAddr::new(self.udp.local_addr()?.ip(), 0))?;
+        Ok(Server::from_socket(udp, self.udp_dst, self.tcp_dst, self.default_timeout)?)
+    }
 
-        Ok(Server { udp, udp_dst, tcp, })
+    /// Opens a data connection for the test the echo server set [allocation] aside for.
+    fn connect_data(&mut self, allocation: &Allocation) -> Result<(), TestError> {
+        let mut tcp = TcpStream::connect_timeout(&self.tcp_dst, self.timeout)?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        tcp.write_all(&allocation.data_greeting())?;
+        self.tcp = Some(tcp);
+        self.tcp_pending.clear();
+        Ok(())
     }
 
+    /// Shuts down the data connection and waits for the echo server to close its end, which it
+    /// only does once it has echoed everything sent over it.
+    fn close_data(&mut self) -> Result<(), TestError> {
+        let mut tcp = match self.tcp.take() {
+            Some(tcp) => tcp,
+            None => return Ok(()),
+        };
+        tcp.shutdown(Shutdown::Write)?;
+        let mut buffer = [0u8; 4096];
+        loop {
+            match tcp.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-    /// Attempts to connect to the echo server with a handshake-type message. Used to ensure a
-    /// connection has actually been established
-    fn handshake(&mut self) -> Result<(), io::Error> {
-        pretty_print("LOG", "Handshake", "Beginning handshake.", false);
-        self.tcp.write(HANDSHAKE_MSG)?;
-        let mut response_buffer = vec![0u8; HANDSHAKE_MSG.len()];
-        let bytes_written = self.tcp.read(&mut response_buffer)?;
+    /// Logs how message [message_number] of the test being ran went, if messages are being logged.
+    fn record_message(&self, message_number: u32, result: &Result<Duration, TestError>, connection: Option<&ConnectionTimes>) {
+        if let Some(ref messages) = self.messages {
+            messages.record(message_number, result, connection);
+        }
+    }
 
-        if bytes_written == response_buffer.len() && &response_buffer[..] == HANDSHAKE_MSG {
-            pretty_print("LOG", "Handshake", "Successfully completed handshake.", false);
-            Ok(())
-        } else {
-            pretty_print("ERR", "Handshake", "Failed to complete handshake with echo server.", false);
-            Err(io::Error::new(io::ErrorKind::Other, "Failed to complete handshake with echo server."))
+    /// The data connection of the TCP test being ran.
+    fn data(&self) -> Result<&TcpStream, io::Error> {
+        self.tcp.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "the test has no data connection"))
+    }
+
+    /// Opens a control connection to the echo server, announcing every test, and runs them one
+    /// after another. What the echo server saw of each test is added to its results. Each message
+    /// and test is logged to [log] as soon as it completes, if it's given. Fails only if the echo
+    /// server can't be reached or refuses the tests.
+    pub fn run_tests(&mut self, tests: Vec<Test>, log: Option<Arc<RunLog>>) -> Result<Vec<TestResult>, TestError> {
+        let mut control = ControlChannel::open(self.tcp_dst, &tests, self.default_timeout)?;
+        let results = tests.into_iter().enumerate().map(|(index, test)| {
+            let allocation = control.start_test(index)?;
+            self.messages = log.as_ref().map(|log| RunLog::messages(log, index, &test));
+            let result = self.run_test(test.clone(), &allocation);
+            self.messages = None;
+            let stats = control.finish_test(index)?;
+            let result = result.map(|mut data| {
+                // The client can't see how much of what it streamed arrived, but the echo server can
+                if let Some(ref mut sent) = data.sent_throughput {
+                    sent.bytes_received = stats.tcp_bytes_received;
+                }
+                data.server_stats = Some(stats);
+                if let Some(latency) = data.latency_stats() {
+                    pretty_print("LOG", "Round Trip Times", &format!("min {:?}, median {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}, std dev {:?}",
+                                                                     latency.min, latency.median, latency.p90, latency.p99, latency.p999, latency.max, latency.stddev), false);
+                }
+                data
+            });
+            if let Some(ref log) = log {
+                log.test_finished(index, &test, &result);
+            }
+            result
+        }).collect();
+
+        match control.close() {
+            Ok(stats) => pretty_print("LOG", "Echo Server", &format!("Over every test the echo server received {} bytes, sent {} bytes and echoed {} of {} datagrams",
+                                                                     stats.tcp_bytes_received, stats.tcp_bytes_sent, stats.datagrams_echoed, stats.datagrams_received), false),
+            Err(e) => pretty_print("ERR", "Echo Server", &format!("Failed to end the session, encountered error '{}'", e), false),
         }
+        Ok(results)
     }
 
-    pub fn run_tests(&mut self, tests: Vec<Test>) -> Result<Vec<TestResult>, io::Error> {
-        self.handshake()?;
-        Ok(tests.into_iter().map(|x| self.run_test(x)).collect())
+    pub fn run_test(&mut self, test: Test, allocation: &Allocation) -> TestResult {
+        match test.spec().parallel {
+            Some(streams) if streams > 1 => self.run_parallel_test(test, streams, allocation),
+            _ => self.run_single_test(test, allocation),
+        }
+    }
+
+    /// Runs [test] over [streams] connections (or sockets) at once: this server's, and one more
+    /// opened for each other stream.
+    fn run_parallel_test(&mut self, test: Test, streams: u32, allocation: &Allocation) -> TestResult {
+        pretty_print("LOG", "Parallel Test", &format!("Running {} over {} streams", test, streams), false);
+        let mut others = (1..streams).map(|_| self.open_stream()).collect::<Result<Vec<Server>, TestError>>()?;
+        if let Some(messages) = self.messages.take() {
+            let num_messages = test.spec().num_messages;
+            for (i, other) in others.iter_mut().enumerate() {
+                other.messages = Some(messages.for_stream(i + 2, num_messages));
+            }
+            self.messages = Some(messages.for_stream(1, num_messages));
+        }
+
+        let results: Vec<TestResult> = thread::scope(|scope| {
+            let handles: Vec<_> = Some(self).into_iter().chain(others.iter_mut())
+                .map(|server| {
+                    let test = test.clone();
+                    scope.spawn(move || server.run_single_test(test, allocation))
+                })
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(TestError::Io(io::Error::other("a stream's thread panicked")))))
+                .collect()
+        });
+
+        let streams = results.into_iter().collect::<Result<Vec<TestData>, TestError>>()?;
+        let combined = TestData::combine(test, streams);
+        let summary = match combined.throughput {
+            Some(ref throughput) => format!("All streams received {} bytes, {:.3} Mbit/s", throughput.bytes_received, throughput.goodput_bps() / 1e6),
+            None => {
+                let summary = combined.summary();
+                format!("All streams echoed {} of {} messages", summary.successful, summary.messages)
+            },
+        };
+        pretty_print("LOG", "Parallel Test", &summary, false);
+        Ok(combined)
     }
 
-    pub fn run_test(&mut self, test: Test) -> TestResult {
+    fn run_single_test(&mut self, test: Test, allocation: &Allocation) -> TestResult {
+        self.timeout = test.spec().timeout_ms.map(Duration::from_millis).unwrap_or(self.default_timeout);
+        self.udp.set_read_timeout(Some(self.timeout))?;
+
         match test {
-            Test::UdpTest(spec) => self.run_udp_test(spec),
-            Test::TcpTest(spec) => self.run_tcp_test(spec)
+            Test::UdpTest(spec) => self.run_udp_test(spec, allocation.test_id),
+            Test::TcpTest(spec) => {
+                // Tests that open a connection for every message do so as they go
+                if !spec.connect_per_message {
+                    self.connect_data(allocation)?;
+                }
+                let result = self.run_tcp_test(spec, allocation);
+                self.tcp = None;
+                result
+            },
         }
     }
 
-    /// Attempts to read enough bytes to fill [buf], from the proper address (the address of the
-    /// echo server). If more than [TIMEOUT_DURATION] seconds pass, this method fails and returns
-    /// Err(None).
-    fn udp_read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Option<io::Error>> {
-        let initial_len = buf.len();
-        let start_time = Instant::now();
+    /// Waits up to [wait] for the echo of message [seq], which should be [len] bytes long, using
+    /// [buffer] to receive datagrams. Every echo that arrives in the meantime is classified by
+    /// [tracker]. If [seq] is None this only classifies echoes until [wait] runs out.
+    fn udp_receive_echo(&mut self, len: usize, buffer: &mut [u8], tracker: &mut ProbeTracker, seq: Option<u32>, wait: Duration)
+        -> Result<Duration, TestError> {
+        let deadline = Instant::now() + wait;
         loop {
-            let (bytes_written, src_address) = self.udp.recv_from(buf)?;
-            if src_address != self.udp_dst {
-                return Err(None)
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::new(0, 0) {
+                return Err(TestError::Timeout)
             }
+            self.udp.set_read_timeout(Some(remaining))?;
 
-            if bytes_written == buf.len() {
-                break
+            let (bytes_read, src_address) = self.udp.recv_from(buffer)?;
+            if src_address != self.udp_dst {
+                return Err(TestError::WrongPeer(src_address))
             }
-
-            let tmp = buf;
-            buf = &mut tmp[bytes_written..];
-
-            if start_time.elapsed() > TIMEOUT_DURATION() {
-                pretty_print("ERR", "UDP Timeout", &format!("Timed out trying to receive {} bytes.", initial_len), false);
-                return Err(None)
+            let datagram = &buffer[..bytes_read];
+            match tracker.receive(datagram, Instant::now()) {
+                // The echo can arrive just after the timeout by the tracker's clock even if it's
+                // still before the deadline here, and then it counts as late
+                Some(echo) if Some(echo.header.seq) == seq && echo.arrival == Arrival::Late => return Err(TestError::Timeout),
+                Some(echo) if Some(echo.header.seq) == seq && echo.arrival != Arrival::Duplicate => {
+                    if bytes_read != len {
+                        return Err(TestError::ShortRead { expected: len, received: bytes_read })
+                    }
+                    echo.header.check_payload(datagram)?;
+                    return Ok(echo.rtt)
+                },
+                Some(echo) => pretty_print("DBG", "UDP Probe", &format!("Received {:?} echo of message #{}", echo.arrival, echo.header.seq), false),
+                None if bytes_read < PROBE_HEADER_LEN => return Err(TestError::ShortRead { expected: len, received: bytes_read }),
+                None => pretty_print("DBG", "UDP Probe", "Ignoring a datagram that wasn't sent by this test", false),
             }
         }
-        Ok(())
     }
 
-    /// Attempts to read enough to fill [buf]. If more than [TIMEOUT_DURATION] seconds pass, this
-    /// method fails and returns Err(None)
-    fn tcp_read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Option<io::Error>> {
-        let initial_len = buf.len();
-        let start_time = Instant::now();
-        loop {
-            let bytes_written = self.tcp.read(buf)?;
-
-            if bytes_written == buf.len() {
-                break
+    /// Reads the echo of message [seq] into [payload]. Echoes of earlier messages that were given
+    /// up on are skipped when they arrive first, and so is anything that isn't a frame at all (like
+    /// the rest of an echo that was only partly read before timing out) until the next frame
+    /// header, so one timeout doesn't throw off every message after it.
+    ///
+    /// Returns when the first byte of the echo arrived, or when this started reading if it had
+    /// already been read along with an earlier echo.
+    /// If the echo doesn't start arriving within [self.timeout] this fails with
+    /// [TestError::Timeout], and if only part of it arrives in that time (or before the connection
+    /// is closed) it fails with [TestError::ShortRead].
+    fn tcp_read_exact(&mut self, seq: u32, payload: &mut [u8]) -> Result<Instant, TestError> {
+        let tcp = match self.tcp {
+            Some(ref tcp) => tcp,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "the test has no data connection").into()),
+        };
+        let pending = &mut self.tcp_pending;
+        let deadline = Instant::now() + self.timeout;
+        let expected = payload.len();
+        let timed_out = |e: TestError, received: usize| {
+            if let TestError::Timeout = e {
+                pretty_print("ERR", "TCP Timeout", &format!("Timed out trying to receive {} bytes.", expected), false);
+            }
+            match e {
+                TestError::Timeout if received > 0 => TestError::ShortRead { expected, received },
+                TestError::ShortRead { .. } => TestError::ShortRead { expected, received },
+                e => e,
             }
+        };
 
-            let tmp = buf;
-            buf = &mut tmp[bytes_written..];
+        fill_pending(tcp, pending, 1, deadline).map_err(|e| timed_out(e, 0))?;
+        let first_byte = Instant::now();
 
-            if start_time.elapsed() > TIMEOUT_DURATION() {
-                pretty_print("ERR", "TCP Timeout",
-                             &format!("Timed out trying to receive {} bytes.", initial_len),
-                             false);
-                return Err(None)
+        let skipped = find_frame(tcp, pending, seq, expected, deadline).map_err(|e| timed_out(e, 0))?;
+        if skipped > 0 {
+            pretty_print("LOG", "TCP Echo", &format!("Skipped {} bytes before the echo of message #{}", skipped, seq), false);
+        }
+
+        // Some of the payload may have been read along with the header
+        let mut received = pending.len().min(expected);
+        payload[..received].copy_from_slice(&pending[..received]);
+        pending.drain(..received);
+        while received < expected {
+            match (&*tcp).read(&mut payload[received..]) {
+                Ok(0) => return Err(TestError::ShortRead { expected, received }),
+                Ok(bytes_read) => received += bytes_read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(timed_out(e.into(), received)),
+            }
+            if received < expected && Instant::now() > deadline {
+                return Err(timed_out(TestError::Timeout, received))
             }
         }
-        Ok(())
+        Ok(first_byte)
     }
 
     /// Sends a udp message to the echo server, and waits for it to be echoed back.
-    fn udp_message(&mut self, message: &mut [u8], test_string: &str, message_number: u32) -> Option<Duration> {
-        // Copy the current message number (nth message), which is a u32, into the message.
-        let message_bytes = unsafe { mem::transmute::<u32, [u8; 4]>(message_number) };
-        for p in 0..message.len() {
-            message[p] = message_bytes[p & 3];
-        }
+    fn udp_message(&mut self, message: &mut [u8], buffer: &mut [u8], tracker: &mut ProbeTracker, test_string: &str, message_number: u32)
+        -> Result<Duration, TestError> {
+        // Stamp the message with a header identifying it and when it was sent
+        tracker.header(message_number).fill(message);
 
-        let now = Instant::now();
-
-        // Try to send the data. If this fails, continue to the next message after pushing
-        // None for the duration of this message
+        // Try to send the data. If this fails, continue to the next message, which will record
+        // why this one failed
         match self.udp.send_to(message, self.udp_dst) {
             Ok(_bytes_sent) => {
                 pretty_print("LOG",
-                             &test_string,
+                             test_string,
                              &format!("Sent message #{}", message_number),
                              true)
             },
             Err(e) => {
                 pretty_print("LOG",
-                             &test_string,
+                             test_string,
                              &format!("Failed to send message #{}, encountered error {:?}", message_number, e),
                              false);
                 // Failed to send the packet, so there is no duration for this message
-                return None;
+                return Err(e.into());
             }
         };
 
-        // Actually receive data, ensure the source is the proper address
-        match self.udp_read_exact(message) {
-            Err(Some(e)) => {
-                pretty_print("ERR",
-                             &test_string,
-                             &format!("Encountered error {:?} while trying to receive data. Trying again.", e),
-                             false);
-                None
-            },
-            Err(None) => {
+        // Actually receive data, ensure the source is the proper address and that it's the same
+        // data we sent
+        let timeout = self.timeout;
+        match self.udp_receive_echo(message.len(), buffer, tracker, Some(message_number), timeout) {
+            Ok(rtt) => Ok(rtt),
+            Err(e) => {
                 pretty_print("ERR",
-                             &test_string,
-                             "Timed out while trying to receive data.",
+                             test_string,
+                             &format!("Failed to receive message #{}: {}", message_number, e),
                              false);
-                None
-            },
-            Ok(()) => {
-                // Check if its the same data we sent (all bytes set to i)
-
-                if (0..message.len())
-                    .map(|x| message[x] == message_bytes[x & 3])
-                    .fold(true, |x, y| x && y) {
-                    Some(now.elapsed())
-                } else {
-                    None
-                }
+                Err(e)
             },
         }
     }
-    fn run_udp_test(&mut self, test_spec: TestSpec) -> TestResult {
+
+    /// Runs a UDP test, whose probes carry [test_id] so the echo server can count them.
+    fn run_udp_test(&mut self, test_spec: TestSpec, test_id: u32) -> TestResult {
 
         let mut s = DefaultHasher::new();
         test_spec.hash(&mut s);
         let test_hash = s.finish();
         let test_string = format!("Test #{}", test_hash);
-        let mut message = vec![0u8; test_spec.message_len];
+        // Every datagram needs room for the probe header
+        let mut message = vec![0u8; test_spec.datagram_len()];
+        let mut buffer = vec![0u8; MAX_UDP_MESSAGE_LEN + 1];
+
+        let mut tracker = ProbeTracker::new(test_id, test_spec.num_messages, self.timeout);
 
         pretty_print("LOG", &test_string,
                      &format!("Beginning UDP test with test spec {:?}\n", test_spec),
                      false);
 
 
-        let durations: Vec<Option<Duration>> =
-            (0..test_spec.num_messages)
-            .map(|i| self.udp_message(&mut message, &test_string, i))
-            .collect();
+        let interval = test_spec.send_interval();
+        let start = Instant::now();
+        let (results, send_duration) = if test_spec.window.is_some() || interval.is_some() {
+            let (results, send_duration) =
+                self.udp_pipelined(&mut message, &mut tracker, &test_string, test_spec.num_messages, test_spec.window, interval)?;
+            (results, Some(send_duration))
+        } else {
+            let results: Vec<Result<Duration, TestError>> = (0..test_spec.num_messages)
+                .map(|i| {
+                    let result = self.udp_message(&mut message, &mut buffer, &mut tracker, &test_string, i);
+                    self.record_message(i, &result, None);
+                    result
+                })
+                .collect();
+            (results, None)
+        };
+        // Waiting for late echoes isn't part of the test
+        let total = start.elapsed();
+        let (durations, dropped_messages) = collect_results(results);
 
+        if !dropped_messages.is_empty() {
+            let grace = LATE_ARRIVAL_GRACE.min(self.timeout);
+            let _ = self.udp_receive_echo(message.len(), &mut buffer, &mut tracker, None, grace);
+        }
 
-        let mut dropped_messages: Vec<u32> = vec![];
-        let mut total: Duration = Duration::new(0, 0);
-        for i in 0usize..durations.len() {
-            if let Some(message_duration) = durations[i] {
-                total += message_duration;
-            } else {
-                    dropped_messages.push(i as u32);
-            }
+        let data = TestData {
+            datagrams: tracker.finish(),
+            send_duration,
+            ..TestData::new(Test::UdpTest(test_spec), total, durations, dropped_messages)
+        };
+        if let (Some(target), Some(achieved)) = (data.target_bps(), data.achieved_bps()) {
+            let jitter = data.jitter().map_or("unknown".to_string(), |jitter| format!("{:?}", jitter));
+            pretty_print("LOG", &test_string,
+                         &format!("Sent at {:.3} Mbit/s (target {:.3} Mbit/s), {:.2}% lost, jitter {}",
+                                  achieved / 1e6, target / 1e6, data.loss_percent(), jitter),
+                         false);
         }
+        Ok(data)
+    }
 
-        Ok(TestData {
-            dropped_messages,
-            test: Test::UdpTest(test_spec),
-            individual_durations: durations,
-            total_duration: total,
-        })
+    /// Sends [num_messages] messages without waiting for each one to be echoed. This thread sends
+    /// the messages, while another thread receives the echoes on a clone of the socket and
+    /// matches them to messages by their sequence number. At most [window] messages are in flight
+    /// at once, if it is given, and messages that aren't echoed within the timeout leave the
+    /// window. If [interval] is given a message is sent every [interval]. Returns the result of
+    /// every message, and how long it took to send them all.
+    fn udp_pipelined(&mut self, message: &mut [u8], tracker: &mut ProbeTracker, test_string: &str, num_messages: u32,
+                     window: Option<u32>, interval: Option<Duration>)
+        -> Result<(Vec<Result<Duration, TestError>>, Duration), TestError> {
+        let window = window.map_or(usize::MAX, |window| window as usize);
+        let receiver = self.udp.try_clone()?;
+        let state = Mutex::new(Window {
+            tracker,
+            in_flight: BTreeMap::new(),
+            results: (0..num_messages).map(|_| None).collect(),
+            done_sending: false,
+            receiver_stopped: false,
+            messages: self.messages.as_ref(),
+        });
+        let slot_freed = Condvar::new();
+        let (udp, udp_dst, timeout, message_len) = (&self.udp, self.udp_dst, self.timeout, message.len());
+
+        let start = Instant::now();
+        let (received, send_duration) = thread::scope(|scope| {
+            let receive = scope.spawn(|| receive_window(receiver, udp_dst, message_len, timeout, &state, &slot_freed));
+
+            for seq in 0..num_messages {
+                // Sends are scheduled from the start of the test rather than the last send, so a
+                // send that runs late doesn't push back every send after it
+                if let Some(interval) = interval {
+                    wait_until(start + interval * seq);
+                }
+                let mut window_state = slot_freed.wait_while(state.lock().unwrap(), |w| w.in_flight.len() >= window && !w.receiver_stopped).unwrap();
+                if window_state.receiver_stopped {
+                    break
+                }
+                window_state.tracker.header(seq).fill(message);
+                window_state.in_flight.insert(seq, Instant::now());
+                drop(window_state);
+
+                if let Err(e) = udp.send_to(message, udp_dst) {
+                    let mut window_state = state.lock().unwrap();
+                    window_state.in_flight.remove(&seq);
+                    window_state.finish(seq, Err(e.into()));
+                }
+            }
+            state.lock().unwrap().done_sending = true;
+            let send_duration = start.elapsed();
+
+            (receive.join().unwrap_or_else(|_| Err(io::Error::other("the thread receiving echoes panicked"))), send_duration)
+        });
+        received?;
+
+        // Every message has either been echoed or timed out by the time the receiver returns
+        let results: Vec<Result<Duration, TestError>> = state.into_inner().unwrap().results.into_iter()
+            .map(|result| result.unwrap_or(Err(TestError::Timeout)))
+            .collect();
+        for (i, result) in results.iter().enumerate() {
+            if let Err(ref e) = *result {
+                pretty_print("ERR", test_string, &format!("Failed to receive message #{}: {}", i, e), false);
+            }
+        }
+        Ok((results, send_duration))
     }
 
-    fn tcp_message(&mut self, message: &mut [u8], test_string: &str, message_number: u32) -> Option<Duration> {
+    /// Sends a TCP message to the echo server in [frame], which is the message with room for a
+    /// frame header before it, and waits for it to be echoed back. Returns how long it took for
+    /// the first byte of the echo to arrive, and for all of it to.
+    fn tcp_message(&mut self, frame: &mut [u8], test_string: &str, message_number: u32) -> Result<(Duration, Duration), TestError> {
+        let message_len = frame.len() - FRAME_HEADER_LEN;
+        FrameHeader { seq: message_number, len: message_len as u32 }.write(frame);
         // Copy the current message number (nth message), which is a u32, into the message.
-        let message_bytes = unsafe { mem::transmute::<u32, [u8; 4]>(message_number) };
-        for p in 0..message.len() {
-            message[p] = message_bytes[p & 3];
+        let message_bytes = message_number.to_ne_bytes();
+        for p in 0..message_len {
+            frame[FRAME_HEADER_LEN + p] = message_bytes[p & 3];
         }
 
         // To measure how long it takes to send and receive the message
         let now = Instant::now();
 
-        match self.tcp.write_all(message) {
-            Ok(_bytes_sent) => {
+        let received = if frame.len() > CONCURRENT_WRITE_LEN {
+            self.tcp_write_while_reading(frame, message_number)
+        } else {
+            self.data().and_then(|mut tcp| tcp.write_all(frame)).map(|_| self.tcp_read_exact(message_number, &mut frame[FRAME_HEADER_LEN..]))
+        };
+        let received = match received {
+            Ok(received) => {
                 pretty_print("LOG",
                              test_string,
                              &format!("Sent message #{}", message_number),
                              true);
+                received
             },
             Err(e) => {
                 pretty_print("LOG",
@@ -232,70 +510,580 @@ impl Server {
                              &format!("Failed to send message #{}, encountered error {:?}", message_number, e),
                              false);
                 // Failed to send the packet, so there is no duration for this message
-                return None;
+                return Err(e.into());
             }
         };
 
-        match self.tcp_read_exact(message) {
-            Ok(()) => {
-                // Check if its the same data we sent (all bytes set to i)
-                if (0..message.len())
-                        .map(|x| message[x] == message_bytes[x & 3])
-                        .fold(true, |x, y| x && y) {
-                    Some(now.elapsed())
-                // It was something else, so lets just ignore it and try again
-                } else {
-                    None
-                }
-            },
-            Err(Some(e)) => {
+        let round_trip = now.elapsed();
+        match received.and_then(|first_byte| check_payload(&frame[FRAME_HEADER_LEN..], &message_bytes).map(|()| first_byte)) {
+            Ok(first_byte) => Ok((first_byte.saturating_duration_since(now), round_trip)),
+            Err(e) => {
                 pretty_print("ERR",
                              test_string,
-                             &format!("Encountered error {:?} while trying to receive data. Trying again.", e),
+                             &format!("Failed to receive message #{}: {}", message_number, e),
                              false);
-                None
+                Err(e)
             },
-            Err(None) => {
-                pretty_print("ERR",
-                             test_string,
-                             "Timed out while trying to receive data",
-                             false);
-                None
-            }
         }
     }
 
-    fn run_tcp_test(&mut self, test_spec: TestSpec) -> TestResult {
+    /// Writes [frame] (message [seq]) from another thread while the echo of its message is read
+    /// back into it. The outer error is from writing the frame, the inner one from reading the echo.
+    fn tcp_write_while_reading(&mut self, frame: &mut [u8], seq: u32) -> Result<Result<Instant, TestError>, io::Error> {
+        let mut writer = self.data()?.try_clone()?;
+        let sent = frame.to_vec();
+        thread::scope(|scope| {
+            let write = scope.spawn(move || writer.write_all(&sent));
+            let received = self.tcp_read_exact(seq, &mut frame[FRAME_HEADER_LEN..]);
+            match write.join() {
+                Ok(Ok(())) => Ok(received),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(io::Error::other("the thread writing the message panicked")),
+            }
+        })
+    }
+
+    fn run_tcp_test(&mut self, test_spec: TestSpec, allocation: &Allocation) -> TestResult {
 
         let mut s = DefaultHasher::new();
         test_spec.hash(&mut s);
         let test_hash = s.finish();
         let test_string = format!("Test #{}", test_hash);
-        let mut message = vec![0u8; test_spec.message_len];
+        let mut frame = vec![0u8; FRAME_HEADER_LEN + test_spec.message_len];
 
         pretty_print("LOG", &test_string, &format!("Beginning TCP test with test spec {:?}\n", test_spec), false);
 
+        if test_spec.direction != Direction::Echo {
+            return self.run_stream_test(test_spec, &test_string)
+        }
+        if test_spec.is_bulk() {
+            return self.run_bulk_tcp_test(test_spec, &test_string)
+        }
+        if test_spec.connect_per_message {
+            return self.run_connection_test(test_spec, allocation, &test_string)
+        }
 
-        let durations: Vec<Option<Duration>> =
+        let start = Instant::now();
+        let results: Vec<Result<Duration, TestError>> =
             (0..test_spec.num_messages)
-            .map(|i| self.tcp_message(&mut message, &test_string, i))
+            .map(|i| {
+                let result = self.tcp_message(&mut frame, &test_string, i).map(|(_, round_trip)| round_trip);
+                self.record_message(i, &result, None);
+                result
+            })
             .collect();
+        let total = start.elapsed();
+        let (durations, dropped_messages) = collect_results(results);
 
-        let mut dropped_messages: Vec<u32> = vec![];
-        let mut total: Duration = Duration::new(0, 0);
-        for i in 0usize..durations.len() {
-            if let Some(message_duration) = durations[i] {
-                total += message_duration;
-            } else {
-                dropped_messages.push(i as u32);
-            }
+        Ok(TestData::new(Test::TcpTest(test_spec), total, durations, dropped_messages))
+    }
+
+    /// Echoes every message over a connection of its own, recording how long it took to connect,
+    /// for the first byte of the echo to arrive, for all of it to arrive, and to close the
+    /// connection again.
+    fn run_connection_test(&mut self, test_spec: TestSpec, allocation: &Allocation, test_string: &str) -> TestResult {
+        let mut frame = vec![0u8; FRAME_HEADER_LEN + test_spec.message_len];
+        let mut connections = Vec::with_capacity(test_spec.num_messages as usize);
+        let start = Instant::now();
+        let results: Vec<Result<Duration, TestError>> =
+            (0..test_spec.num_messages)
+            .map(|i| {
+                let (result, times) = match self.connection_message(&mut frame, test_string, allocation, i) {
+                    Ok(times) => (Ok(times.round_trip), Some(times)),
+                    Err(e) => (Err(e), None),
+                };
+                self.record_message(i, &result, times.as_ref());
+                connections.push(times);
+                result
+            })
+            .collect();
+        let total = start.elapsed();
+        let (durations, dropped_messages) = collect_results(results);
+
+        let data = TestData { connections, ..TestData::new(Test::TcpTest(test_spec), total, durations, dropped_messages) };
+        if let Some(average) = data.average_connection_times() {
+            pretty_print("LOG", test_string, &format!("On average connecting took {:?}, the first byte of an echo took {:?}, all of it took {:?} and closing took {:?}",
+                                                      average.connect, average.first_byte, average.round_trip, average.close), false);
+        }
+        Ok(data)
+    }
+
+    /// Opens a connection, echoes message [message_number] over it, and closes it again.
+    fn connection_message(&mut self, frame: &mut [u8], test_string: &str, allocation: &Allocation, message_number: u32)
+        -> Result<ConnectionTimes, TestError> {
+        let connecting = Instant::now();
+        if let Err(e) = self.connect_data(allocation) {
+            pretty_print("ERR", test_string, &format!("Failed to connect for message #{}: {}", message_number, e), false);
+            return Err(e)
+        }
+        let connect = connecting.elapsed();
+
+        let echoed = self.tcp_message(frame, test_string, message_number);
+        let closing = Instant::now();
+        let closed = self.close_data();
+        let close = closing.elapsed();
+        let (first_byte, round_trip) = echoed?;
+        if let Err(e) = closed {
+            pretty_print("ERR", test_string, &format!("Failed to close the connection of message #{}: {}", message_number, e), false);
+            return Err(e)
+        }
+        Ok(ConnectionTimes { connect, first_byte, round_trip, close })
+    }
+
+    /// Streams data through the echo server until [test_spec.duration_ms] has passed or
+    /// [test_spec.total_bytes] have been sent, whichever comes first. The data is written from
+    /// another thread in chunks of [test_spec.message_len] bytes, while this thread reads the echo,
+    /// checks it, and counts how much of it arrives during each interval.
+    fn run_bulk_tcp_test(&mut self, test_spec: TestSpec, test_string: &str) -> TestResult {
+        let chunk_len = test_spec.message_len;
+        let duration = test_spec.duration_ms.map(Duration::from_millis);
+        let interval = test_spec.interval_ms.map(Duration::from_millis).unwrap_or(DEFAULT_SAMPLE_INTERVAL);
+        let pattern = bulk_pattern(chunk_len.max(BULK_READ_LEN));
+
+        let mut writer = self.data()?.try_clone()?;
+        let bytes_sent = AtomicU64::new(0);
+        let done_sending = AtomicBool::new(false);
+        // Reads wake up regularly to notice when the writer is done
+        self.data()?.set_read_timeout(Some(BULK_POLL_INTERVAL))?;
+
+        let start = Instant::now();
+        let (written, read) = thread::scope(|scope| {
+            let write = scope.spawn(|| {
+                let result = control::write_pattern(&mut writer, &pattern, chunk_len, duration, test_spec.total_bytes, start,
+                                                    |sent| bytes_sent.store(sent, Ordering::SeqCst));
+                done_sending.store(true, Ordering::SeqCst);
+                result
+            });
+
+            let read = self.bulk_read(&pattern, start, interval, Some((&bytes_sent, &done_sending)));
+            (write.join().unwrap_or_else(|_| Err(io::Error::other("the thread writing the stream panicked"))), read)
+        });
+        self.data()?.set_read_timeout(Some(self.timeout))?;
+        written?;
+        let (bytes_received, interval_bytes) = read?;
+
+        let throughput = Throughput {
+            bytes_sent: bytes_sent.into_inner(),
+            bytes_received,
+            duration: start.elapsed(),
+            interval,
+            interval_bytes,
+        };
+        for (i, bps) in throughput.interval_bps().iter().enumerate() {
+            pretty_print("DBG", test_string, &format!("Interval #{}: {:.3} Mbit/s", i, bps / 1e6), false);
+        }
+        pretty_print("LOG", test_string, &format!("Echoed {} bytes in {:?}, {:.3} Mbit/s",
+                                                  throughput.bytes_received, throughput.duration, throughput.goodput_bps() / 1e6), false);
+
+        let total = throughput.duration;
+        Ok(TestData { throughput: Some(throughput), ..TestData::new(Test::TcpTest(test_spec), total, vec![], vec![]) })
+    }
+
+    /// Runs a reverse or bidirectional bulk test. The echo server starts streaming to the data
+    /// connection as soon as it's opened, and for bidirectional tests the pattern is streamed to
+    /// the echo server from another thread at the same time. The echo server only closes the
+    /// connection once it has read everything sent to it, so the test is over once the connection
+    /// is closed.
+    fn run_stream_test(&mut self, test_spec: TestSpec, test_string: &str) -> TestResult {
+        let chunk_len = test_spec.message_len;
+        let duration = test_spec.duration_ms.map(Duration::from_millis);
+        let interval = test_spec.interval_ms.map(Duration::from_millis).unwrap_or(DEFAULT_SAMPLE_INTERVAL);
+        let pattern = bulk_pattern(chunk_len.max(BULK_READ_LEN));
+
+        let mut writer = self.data()?.try_clone()?;
+        // Reads only wake up to check the timeout, since the test is over when the connection is closed
+        self.data()?.set_read_timeout(Some(BULK_POLL_INTERVAL))?;
+
+        let start = Instant::now();
+        let (written, read) = thread::scope(|scope| {
+            let write = match test_spec.direction {
+                Direction::Bidirectional => Some(scope.spawn(|| {
+                    let mut interval_bytes: Vec<u64> = vec![];
+                    let mut counted = 0;
+                    let sent = control::write_pattern(&mut writer, &pattern, chunk_len, duration, test_spec.total_bytes, start, |sent| {
+                        add_to_interval(&mut interval_bytes, start, interval, sent - counted);
+                        counted = sent;
+                    })?;
+                    // Lets the echo server know everything has been sent
+                    writer.shutdown(Shutdown::Write)?;
+                    Ok((sent, interval_bytes))
+                })),
+                _ => None,
+            };
+
+            let read = self.bulk_read(&pattern, start, interval, None);
+            let written = write.map(|handle| handle.join().unwrap_or_else(|_| Err(io::Error::other("the thread writing the stream panicked"))));
+            (written, read)
+        });
+        let elapsed = start.elapsed();
+        let (bytes_received, interval_bytes) = read?;
+        let sent_throughput = match written {
+            // Everything that was sent arrived, since the echo server read it all before closing
+            Some(written) => {
+                let (bytes_sent, interval_bytes) = written?;
+                Some(Throughput { bytes_sent, bytes_received: bytes_sent, duration: elapsed, interval, interval_bytes })
+            },
+            None => None,
+        };
+
+        let throughput = Throughput {
+            bytes_sent: bytes_received,
+            bytes_received,
+            duration: elapsed,
+            interval,
+            interval_bytes,
+        };
+        pretty_print("LOG", test_string, &format!("Received {} bytes from the echo server in {:?}, {:.3} Mbit/s",
+                                                  throughput.bytes_received, throughput.duration, throughput.goodput_bps() / 1e6), false);
+        if let Some(ref sent) = sent_throughput {
+            pretty_print("LOG", test_string, &format!("Sent {} bytes to the echo server in {:?}, {:.3} Mbit/s",
+                                                      sent.bytes_sent, sent.duration, sent.goodput_bps() / 1e6), false);
         }
 
         Ok(TestData {
-            dropped_messages,
-            test: Test::TcpTest(test_spec),
-            individual_durations: durations,
-            total_duration: total,
+            throughput: Some(throughput),
+            sent_throughput,
+            ..TestData::new(Test::TcpTest(test_spec), elapsed, vec![], vec![])
         })
     }
+
+    /// Reads the echo of a bulk test until the writer is done and everything it sent has been
+    /// echoed, checking it against [pattern]. [echo_of] is how much the writer has sent so far and
+    /// whether it's done; without it everything is read until the echo server closes the
+    /// connection. Returns how many bytes were received, in total and during each [interval] since
+    /// [start]. Fails if nothing arrives for longer than the timeout.
+    fn bulk_read(&self, pattern: &[u8], start: Instant, interval: Duration, echo_of: Option<(&AtomicU64, &AtomicBool)>)
+        -> Result<(u64, Vec<u64>), TestError> {
+        let mut buffer = vec![0u8; BULK_READ_LEN];
+        let mut received = 0u64;
+        let mut interval_bytes: Vec<u64> = vec![];
+        let mut last_progress = Instant::now();
+        let expected = || echo_of.map_or(0, |(bytes_sent, _)| bytes_sent.load(Ordering::SeqCst) as usize);
+        loop {
+            // The writer is done before its last bytes are counted here, so check it first
+            if let Some((bytes_sent, done_sending)) = echo_of {
+                if done_sending.load(Ordering::SeqCst) && received == bytes_sent.load(Ordering::SeqCst) {
+                    return Ok((received, interval_bytes))
+                }
+            }
+
+            let bytes_read = match self.data()?.read(&mut buffer) {
+                Ok(0) if echo_of.is_none() => return Ok((received, interval_bytes)),
+                Ok(0) => return Err(TestError::ShortRead { expected: expected(), received: received as usize }),
+                Ok(bytes_read) => bytes_read,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {
+                    if last_progress.elapsed() > self.timeout {
+                        return match echo_of {
+                            Some(_) => Err(TestError::ShortRead { expected: expected(), received: received as usize }),
+                            None => Err(TestError::Timeout),
+                        }
+                    }
+                    continue
+                },
+                Err(e) => return Err(e.into()),
+            };
+            last_progress = Instant::now();
+
+            let offset = (received % BULK_PATTERN_LEN as u64) as usize;
+            if let Some(i) = (0..bytes_read).find(|&i| buffer[i] != pattern[offset + i]) {
+                return Err(TestError::PayloadMismatch { offset: received as usize + i })
+            }
+
+            add_to_interval(&mut interval_bytes, start, interval, bytes_read as u64);
+            received += bytes_read as u64;
+        }
+    }
+}
+
+/// Reads from [tcp] until [pending] holds at least [len] bytes, or [deadline] passes.
+fn fill_pending<R: Read>(mut tcp: R, pending: &mut Vec<u8>, len: usize, deadline: Instant) -> Result<(), TestError> {
+    let mut buffer = [0u8; 4096];
+    while pending.len() < len {
+        match tcp.read(&mut buffer) {
+            Ok(0) => return Err(TestError::ShortRead { expected: len, received: pending.len() }),
+            Ok(bytes_read) => pending.extend_from_slice(&buffer[..bytes_read]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+        if pending.len() < len && Instant::now() > deadline {
+            return Err(TestError::Timeout)
+        }
+    }
+    Ok(())
+}
+
+/// Reads from [tcp] into [pending] until it starts with the header of the echo of message [seq],
+/// whose payload is [len] bytes long, then removes the header. Everything before it is skipped:
+/// the late echoes of earlier messages whole, and anything else up to where a header could start.
+/// Returns how many bytes were skipped.
+fn find_frame<R: Read>(mut tcp: R, pending: &mut Vec<u8>, seq: u32, len: usize, deadline: Instant) -> Result<usize, TestError> {
+    let mut skipped = 0;
+    loop {
+        fill_pending(&mut tcp, pending, FRAME_HEADER_LEN, deadline)?;
+        match FrameHeader::read(pending) {
+            Some(header) if header.seq == seq && header.len as usize == len => {
+                pending.drain(..FRAME_HEADER_LEN);
+                return Ok(skipped)
+            },
+            Some(header) if header.seq < seq => {
+                pretty_print("DBG", "TCP Echo", &format!("Skipping the late echo of message #{}", header.seq), false);
+                let mut remaining = FRAME_HEADER_LEN + header.len as usize;
+                skipped += remaining;
+                while remaining > 0 {
+                    if pending.is_empty() {
+                        fill_pending(&mut tcp, pending, 1, deadline)?;
+                    }
+                    let len = remaining.min(pending.len());
+                    pending.drain(..len);
+                    remaining -= len;
+                }
+            },
+            _ => {
+                let skip = FrameHeader::skippable(pending);
+                skipped += skip;
+                pending.drain(..skip);
+            },
+        }
+    }
+}
+
+/// Counts [bytes] towards the current [interval] since [start].
+fn add_to_interval(interval_bytes: &mut Vec<u64>, start: Instant, interval: Duration, bytes: u64) {
+    let index = (start.elapsed().as_nanos() / interval.as_nanos()) as usize;
+    if interval_bytes.len() <= index {
+        interval_bytes.resize(index + 1, 0);
+    }
+    interval_bytes[index] += bytes;
+}
+
+/// Checks that every byte of [message] is the one it was filled with, which is [message_bytes]
+/// repeated.
+fn check_payload(message: &[u8], message_bytes: &[u8; 4]) -> Result<(), TestError> {
+    match (0..message.len()).find(|&x| message[x] != message_bytes[x & 3]) {
+        Some(offset) => Err(TestError::PayloadMismatch { offset }),
+        None => Ok(()),
+    }
+}
+
+/// Splits the result of every message into the duration of each one (None if it was dropped) and
+/// the messages that were dropped.
+fn collect_results(results: Vec<Result<Duration, TestError>>) -> (Vec<Option<Duration>>, Vec<DroppedMessage>) {
+    let mut durations = Vec::with_capacity(results.len());
+    let mut dropped_messages = vec![];
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(message_duration) => durations.push(Some(message_duration)),
+            Err(e) => {
+                dropped_messages.push(DroppedMessage::new(i as u32, &e));
+                durations.push(None);
+            },
+        }
+    }
+    (durations, dropped_messages)
+}
+
+/// Waits until [deadline] as precisely as possible.
+fn wait_until(deadline: Instant) {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::new(0, 0) {
+            return
+        } else if remaining > SPIN_THRESHOLD {
+            thread::sleep(remaining - SPIN_THRESHOLD);
+        } else {
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Tells the sender of a pipelined UDP test that the receiver stopped when it's dropped, which
+/// happens however [receive_window] returns, even if it panics.
+struct ReceiverStopped<'a, 'b: 'a> {
+    state: &'a Mutex<Window<'b>>,
+    slot_freed: &'a Condvar,
+}
+
+impl<'a, 'b> Drop for ReceiverStopped<'a, 'b> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).receiver_stopped = true;
+        self.slot_freed.notify_all();
+    }
+}
+
+/// What the sender and receiver of a pipelined UDP test share.
+struct Window<'a> {
+    tracker: &'a mut ProbeTracker,
+    /// When each message that is still waiting for its echo was sent
+    in_flight: BTreeMap<u32, Instant>,
+    /// The result of each message, once it has been echoed or timed out
+    results: Vec<Option<Result<Duration, TestError>>>,
+    done_sending: bool,
+    /// Set once the receiver stops, however it stops, so the sender doesn't wait for a slot that
+    /// will never free up
+    receiver_stopped: bool,
+    /// Where each message is logged once it has been echoed or timed out
+    messages: Option<&'a MessageLog>,
+}
+
+impl<'a> Window<'a> {
+    /// Classifies [datagram], and if it's the first echo of a message that is still in flight,
+    /// decides how that message went.
+    fn receive(&mut self, datagram: &[u8], now: Instant, message_len: usize) {
+        let echo = match self.tracker.receive(datagram, now) {
+            Some(echo) => echo,
+            None => {
+                pretty_print("DBG", "UDP Probe", "Ignoring a datagram that wasn't sent by this test", false);
+                return
+            },
+        };
+        if self.in_flight.remove(&echo.header.seq).is_none() {
+            pretty_print("DBG", "UDP Probe", &format!("Received {:?} echo of message #{}", echo.arrival, echo.header.seq), false);
+            return
+        }
+
+        let result = if echo.arrival == Arrival::Late {
+            Err(TestError::Timeout)
+        } else if datagram.len() != message_len {
+            Err(TestError::ShortRead { expected: message_len, received: datagram.len() })
+        } else {
+            echo.header.check_payload(datagram).map(|()| echo.rtt)
+        };
+        self.finish(echo.header.seq, result);
+    }
+
+    /// Records how message [seq] went.
+    fn finish(&mut self, seq: u32, result: Result<Duration, TestError>) {
+        if let Some(messages) = self.messages {
+            messages.record(seq, &result, None);
+        }
+        self.results[seq as usize] = Some(result);
+    }
+
+    /// Gives up on every message that has been in flight for longer than [timeout].
+    fn expire(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let expired: Vec<u32> = self.in_flight.iter()
+            .filter(|&(_, sent)| now.duration_since(*sent) > timeout)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in expired {
+            self.in_flight.remove(&seq);
+            self.finish(seq, Err(TestError::Timeout));
+        }
+    }
+}
+
+/// Receives the echoes of a pipelined UDP test on [udp], until every message has been sent and has
+/// either been echoed or timed out. [slot_freed] is notified whenever messages leave the window.
+fn receive_window(udp: UdpSocket, udp_dst: SocketAddr, message_len: usize, timeout: Duration, state: &Mutex<Window>, slot_freed: &Condvar)
+    -> Result<(), io::Error> {
+    let _stopped = ReceiverStopped { state, slot_freed };
+    let mut buffer = vec![0u8; MAX_UDP_MESSAGE_LEN + 1];
+    loop {
+        // Wake up in time to give up on the oldest message in flight
+        let wait = {
+            let window = state.lock().unwrap();
+            if window.done_sending && window.in_flight.is_empty() {
+                return Ok(())
+            }
+            window.in_flight.values().next()
+                .map(|sent| (*sent + timeout).saturating_duration_since(Instant::now()))
+                .unwrap_or(RECEIVE_POLL_INTERVAL)
+        };
+        udp.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+
+        match udp.recv_from(&mut buffer) {
+            Ok((bytes_read, src_address)) if src_address == udp_dst => {
+                let now = Instant::now();
+                state.lock().unwrap().receive(&buffer[..bytes_read], now, message_len);
+            },
+            Ok((_, src_address)) => pretty_print("DBG", "UDP Probe", &format!("Ignoring a datagram from {}", src_address), false),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+                || e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+
+        state.lock().unwrap().expire(timeout);
+        slot_freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads [bytes] one at a time, like a stream that only ever has part of a frame ready.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0)
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    /// Message [seq] with [payload], as it would be echoed.
+    fn frame(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; FRAME_HEADER_LEN];
+        FrameHeader { seq, len: payload.len() as u32 }.write(&mut frame);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(1)
+    }
+
+    #[test]
+    fn finds_a_header_read_in_pieces() {
+        let stream = frame(4, b"abcd");
+        let mut pending = vec![];
+        assert_eq!(find_frame(Trickle(&stream), &mut pending, 4, 4, deadline()).unwrap(), 0);
+        assert!(pending.is_empty());
+
+        // Part of the header was already read before the rest arrived
+        let mut pending = stream[..5].to_vec();
+        assert_eq!(find_frame(&stream[5..], &mut pending, 4, 4, deadline()).unwrap(), 0);
+        assert_eq!(pending, b"abcd");
+    }
+
+    #[test]
+    fn skips_the_late_echoes_of_earlier_messages() {
+        let stale = [frame(1, b"late"), frame(2, b"later")].concat();
+        let stream = [stale.clone(), frame(3, b"wanted")].concat();
+        let mut pending = vec![];
+        assert_eq!(find_frame(&stream[..], &mut pending, 3, 6, deadline()).unwrap(), stale.len());
+        assert_eq!(pending, b"wanted");
+
+        // The late echo can end part way through what's been read
+        let mut pending = vec![];
+        assert_eq!(find_frame(Trickle(&stream), &mut pending, 3, 6, deadline()).unwrap(), stale.len());
+    }
+
+    #[test]
+    fn skips_garbage_before_a_header() {
+        let stream = [b"xxDL".to_vec(), frame(7, b"ok")].concat();
+        let mut pending = vec![];
+        assert_eq!(find_frame(&stream[..], &mut pending, 7, 2, deadline()).unwrap(), 4);
+        assert_eq!(pending, b"ok");
+
+        // A header that doesn't match the message, and isn't an earlier one, is skipped like garbage
+        let stream = [frame(9, b"no"), frame(7, b"ok")].concat();
+        let mut pending = vec![];
+        assert_eq!(find_frame(&stream[..], &mut pending, 7, 2, deadline()).unwrap(), FRAME_HEADER_LEN + 2);
+        assert_eq!(pending, b"ok");
+    }
+
+    #[test]
+    fn fails_on_a_partial_header_at_the_end_of_the_stream() {
+        let stream = frame(1, b"abcd");
+        let mut pending = vec![];
+        let result = find_frame(&stream[..FRAME_HEADER_LEN - 2], &mut pending, 1, 4, deadline());
+        assert!(matches!(result, Err(TestError::ShortRead { .. })));
+    }
 }
@@ -0,0 +1,222 @@
+use std::time::Duration;
+
+/// Summary statistics of the round trip times of the messages of a test that were echoed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    /// How many round trip times these are of
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    /// The population standard deviation
+    pub stddev: Duration,
+}
+
+impl LatencyStats {
+    /// Summarizes [rtts], which can be in any order. None if there are none.
+    pub fn new(rtts: &[Duration]) -> Option<LatencyStats> {
+        if rtts.is_empty() {
+            return None
+        }
+        let mut sorted = rtts.to_vec();
+        sorted.sort();
+
+        let secs: Vec<f64> = sorted.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|rtt| (rtt - mean) * (rtt - mean)).sum::<f64>() / secs.len() as f64;
+
+        Some(LatencyStats {
+            count: sorted.len(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: Duration::from_secs_f64(mean),
+            median: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p99: percentile(&sorted, 99.0),
+            p999: percentile(&sorted, 99.9),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        })
+    }
+}
+
+/// The [p]th percentile of [sorted], which must be sorted and not empty, interpolating between
+/// the two closest values when it falls between them.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0).clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let (lower, upper) = (sorted[rank.floor() as usize], sorted[rank.ceil() as usize]);
+    lower + (upper - lower).mul_f64(rank.fract())
+}
+
+/// A running average of how much the round trip time changes between consecutive echoes,
+/// smoothed with a gain of 1/16 like the interarrival jitter of RFC 3550, but from round trip
+/// times rather than one way transit times. [rtts] must be in the order the messages were sent.
+/// None if there are fewer than two.
+pub fn jitter(rtts: &[Duration]) -> Option<Duration> {
+    if rtts.len() < 2 {
+        return None
+    }
+    let jitter = rtts.windows(2).fold(0.0, |jitter, pair| {
+        let change = (pair[1].as_secs_f64() - pair[0].as_secs_f64()).abs();
+        jitter + (change - jitter) / 16.0
+    });
+    Some(Duration::from_secs_f64(jitter))
+}
+
+/// The fewest times each side of [mann_whitney_u] needs.
+pub const MIN_MANN_WHITNEY_SAMPLES: usize = 8;
+
+/// The one sided p-value of a Mann-Whitney U test of whether the times in [after] tend to be
+/// longer than those in [before], using the normal approximation with corrections for ties and
+/// continuity. It's only accurate with a handful of times in each, so None if either has fewer
+/// than [MIN_MANN_WHITNEY_SAMPLES].
+pub fn mann_whitney_u(before: &[Duration], after: &[Duration]) -> Option<f64> {
+    if before.len() < MIN_MANN_WHITNEY_SAMPLES || after.len() < MIN_MANN_WHITNEY_SAMPLES {
+        return None
+    }
+    let mut all: Vec<(Duration, bool)> = before.iter().map(|&rtt| (rtt, false))
+        .chain(after.iter().map(|&rtt| (rtt, true)))
+        .collect();
+    all.sort_by_key(|&(rtt, _)| rtt);
+
+    // Tied times all get the average of their ranks
+    let (mut after_ranks, mut ties) = (0.0, 0.0);
+    let mut i = 0;
+    while i < all.len() {
+        let tied = all[i..].iter().take_while(|&&(rtt, _)| rtt == all[i].0).count();
+        let rank = i as f64 + (tied as f64 + 1.0) / 2.0;
+        after_ranks += rank * all[i..i + tied].iter().filter(|&&(_, is_after)| is_after).count() as f64;
+        ties += (tied * tied * tied - tied) as f64;
+        i += tied;
+    }
+
+    let (n1, n2, n) = (before.len() as f64, after.len() as f64, all.len() as f64);
+    let u = after_ranks - n2 * (n2 + 1.0) / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        // Every time is the same
+        return Some(1.0)
+    }
+    let z = (u - n1 * n2 / 2.0 - 0.5) / variance.sqrt();
+    Some(1.0 - normal_cdf(z))
+}
+
+/// The cumulative distribution function of the standard normal distribution, using the
+/// approximation of the error function from Abramowitz and Stegun (7.1.26).
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / 2f64.sqrt();
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t * (0.254_829_592 + t * (-0.284_496_736 + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    if z >= 0.0 { (1.0 + erf) / 2.0 } else { (1.0 - erf) / 2.0 }
+}
+
+/// A histogram of round trip times with a bucket for every power of two nanoseconds, from the
+/// bucket of the shortest time to that of the longest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Histogram {
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// The number of round trip times at least [lower] and shorter than [upper].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct HistogramBucket {
+    pub lower: Duration,
+    pub upper: Duration,
+    pub count: usize,
+}
+
+impl Histogram {
+    pub fn new(rtts: &[Duration]) -> Histogram {
+        // Times under a nanosecond share the first bucket
+        let bucket = |rtt: &Duration| 63 - (rtt.as_nanos().min(u64::MAX as u128) as u64).max(1).leading_zeros();
+        let (first, last) = match (rtts.iter().map(bucket).min(), rtts.iter().map(bucket).max()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Histogram::default(),
+        };
+
+        let mut buckets: Vec<HistogramBucket> = (first..=last).map(|i| HistogramBucket {
+            lower: Duration::from_nanos(1 << i),
+            upper: Duration::from_nanos(1u64.checked_shl(i + 1).unwrap_or(u64::MAX)),
+            count: 0,
+        }).collect();
+        for rtt in rtts {
+            buckets[(bucket(rtt) - first) as usize].count += 1;
+        }
+        Histogram { buckets }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(times: &[u64]) -> Vec<Duration> {
+        times.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    #[test]
+    fn percentile_interpolates_between_times() {
+        let sorted = millis(&[10, 20, 30, 40]);
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(25));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(40));
+        assert_eq!(percentile(&millis(&[7]), 99.0), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn latency_stats_of_one_and_no_times() {
+        assert_eq!(LatencyStats::new(&[]), None);
+        let stats = LatencyStats::new(&millis(&[7])).unwrap();
+        let seven = Duration::from_millis(7);
+        assert_eq!((stats.min, stats.median, stats.p999, stats.max), (seven, seven, seven, seven));
+        assert_eq!(stats.stddev, Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_smooths_changes_in_rtt() {
+        assert_eq!(jitter(&[]), None);
+        assert_eq!(jitter(&millis(&[10])), None);
+        assert_eq!(jitter(&millis(&[10, 10, 10])), Some(Duration::ZERO));
+        // A single change of 16ms moves the jitter a sixteenth of the way there
+        let jitter = jitter(&millis(&[10, 26])).unwrap();
+        assert!(jitter.as_nanos().abs_diff(1_000_000) <= 1, "{:?}", jitter);
+    }
+
+    #[test]
+    fn histogram_buckets_are_powers_of_two() {
+        assert_eq!(Histogram::new(&[]), Histogram::default());
+        let nanos: Vec<Duration> = [0, 1023, 1024, 2047, 4096].iter().map(|&ns| Duration::from_nanos(ns)).collect();
+        let histogram = Histogram::new(&nanos[1..]);
+        let buckets: Vec<(u128, u128, usize)> = histogram.buckets.iter()
+            .map(|bucket| (bucket.lower.as_nanos(), bucket.upper.as_nanos(), bucket.count))
+            .collect();
+        assert_eq!(buckets, vec![(512, 1024, 1), (1024, 2048, 2), (2048, 4096, 0), (4096, 8192, 1)]);
+        // Times under a nanosecond share the first bucket
+        assert_eq!(Histogram::new(&nanos[..1]).buckets[0].lower, Duration::from_nanos(1));
+    }
+
+    #[test]
+    fn mann_whitney_u_finds_longer_times() {
+        let before = millis(&[10, 11, 12, 10, 11, 13, 12, 10, 11, 12]);
+        let after = millis(&[15, 16, 14, 15, 17, 16, 15, 14, 16, 15]);
+        assert!(mann_whitney_u(&before, &after).unwrap() < 0.001);
+        // Shorter times aren't a regression
+        assert!(mann_whitney_u(&after, &before).unwrap() > 0.999);
+    }
+
+    #[test]
+    fn mann_whitney_u_without_a_difference() {
+        let times = millis(&[10, 11, 12, 10, 11, 13, 12, 10, 11, 12]);
+        assert!(mann_whitney_u(&times, &times).unwrap() > 0.4);
+        assert_eq!(mann_whitney_u(&millis(&[5; 10]), &millis(&[5; 10])), Some(1.0));
+    }
+
+    #[test]
+    fn mann_whitney_u_needs_enough_times() {
+        assert_eq!(mann_whitney_u(&millis(&[10, 11, 12]), &millis(&[15, 16, 14, 15, 17, 16, 15, 14])), None);
+    }
+}
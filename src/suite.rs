@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{ Read, self };
+use std::path::Path;
+
+use serde_json;
+use toml;
+
+use test::*;
+
+/// The suite ran by the `required` subcommand.
+pub const REQUIRED_SUITE: &str = include_str!("../suites/required.toml");
+
+/// A named list of tests, loaded from a TOML or JSON scenario file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Suite {
+    /// The name of the scenario. Results are saved under this name.
+    pub name: String,
+    pub tests: Vec<SuiteTest>,
+}
+
+/// A single test in a suite, along with options that only apply to suites.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SuiteTest {
+    /// A name for the test. Defaults to the PROTOCOL:NUM_MESSAGES:MESSAGE_LEN form of the test.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// How many times the test should be ran.
+    #[serde(default = "default_repetitions")]
+    pub repetitions: u32,
+    pub protocol: Protocol,
+    #[serde(flatten)]
+    pub spec: TestSpec,
+}
+
+fn default_repetitions() -> u32 { 1 }
+
+#[derive(Debug)]
+pub enum SuiteError {
+    Io(io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for SuiteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SuiteError::Io(ref e) => write!(f, "failed to read suite: {}", e),
+            SuiteError::Parse(ref e) => write!(f, "failed to parse suite: {}", e),
+            SuiteError::Invalid(ref e) => write!(f, "invalid suite: {}", e),
+        }
+    }
+}
+
+impl Suite {
+    /// Loads a suite from a file. Files ending in `.json` are parsed as JSON, anything else as TOML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Suite, SuiteError> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(SuiteError::Io)?;
+
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Suite::from_json(&contents)
+        } else {
+            Suite::from_toml(&contents)
+        }
+    }
+
+    pub fn from_toml(s: &str) -> Result<Suite, SuiteError> {
+        let value: toml::Value = toml::from_str(s).map_err(|e| SuiteError::Parse(e.to_string()))?;
+        Suite::from_value(serde_json::to_value(value).map_err(|e| SuiteError::Parse(e.to_string()))?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Suite, SuiteError> {
+        Suite::from_value(serde_json::from_str(s).map_err(|e| SuiteError::Parse(e.to_string()))?)
+    }
+
+    /// Deserializes and validates a suite. Since the fields of a [SuiteTest] are flattened, serde
+    /// can't reject unknown fields in them, so that's done here before deserializing.
+    fn from_value(value: serde_json::Value) -> Result<Suite, SuiteError> {
+        let template = SuiteTest { label: None, repetitions: 1, protocol: Protocol::Tcp, spec: TestSpec::default() };
+        let known_fields: BTreeSet<String> = match serde_json::to_value(template) {
+            Ok(serde_json::Value::Object(map)) => map.into_iter().map(|(k, _)| k).collect(),
+            _ => unreachable!("SuiteTest always serializes to an object"),
+        };
+        let tests = value.get("tests").and_then(|t| t.as_array()).cloned().unwrap_or_default();
+        for (i, test) in tests.iter().enumerate() {
+            for key in test.as_object().into_iter().flat_map(|o| o.keys()) {
+                if !known_fields.contains(key) {
+                    return Err(SuiteError::Invalid(format!("test #{} has unknown field '{}'", i + 1, key)))
+                }
+            }
+        }
+
+        let suite: Suite = serde_json::from_value(value).map_err(|e| SuiteError::Parse(e.to_string()))?;
+        suite.validate()?;
+        Ok(suite)
+    }
+
+    fn validate(&self) -> Result<(), SuiteError> {
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(SuiteError::Invalid(format!(
+                "'{}' is not a valid suite name (letters, numbers, '-', '_' and '.' only)", self.name)))
+        }
+        if self.tests.is_empty() {
+            return Err(SuiteError::Invalid("the suite has no tests".to_string()))
+        }
+        for (i, entry) in self.tests.iter().enumerate() {
+            let test = entry.test();
+            if entry.repetitions == 0 {
+                return Err(SuiteError::Invalid(format!("test #{} ({}) has zero repetitions", i + 1, entry.label())))
+            }
+            test.validate().map_err(|e| SuiteError::Invalid(format!("test #{} ({}): {}", i + 1, entry.label(), e)))?;
+        }
+        Ok(())
+    }
+
+    /// Every test to run, with each test repeated as many times as it asks to be, along with the
+    /// label of each one.
+    pub fn expand(&self) -> Vec<(String, Test)> {
+        self.tests.iter()
+            .flat_map(|entry| (0..entry.repetitions).map(move |_| (entry.label(), entry.test())))
+            .collect()
+    }
+}
+
+impl SuiteTest {
+    pub fn test(&self) -> Test {
+        Test::new(self.protocol, self.spec.clone())
+    }
+
+    pub fn label(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.test().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_required_suite() {
+        let suite = Suite::from_toml(REQUIRED_SUITE).unwrap();
+        assert_eq!(suite.name, "required");
+        assert_eq!(suite.expand().len(), 17);
+        assert_eq!(suite.expand()[0].0, "tcp:64:1");
+    }
+
+    #[test]
+    fn rejects_misspelled_fields() {
+        let suite = Suite::from_toml("name = \"typo\"\n[[tests]]\nprotocol = \"tcp\"\nnum_messages = 4\nmessage_len = 8\nrepetition = 2\n");
+        match suite {
+            Err(SuiteError::Invalid(e)) => assert!(e.contains("'repetition'"), "{}", e),
+            result => panic!("expected the misspelled field to be rejected, got {:?}", result),
+        }
+        assert!(Suite::from_json(r#"{"name": "typo", "test": []}"#).is_err());
+    }
+
+    #[test]
+    fn expands_repetitions_with_their_labels() {
+        let suite = Suite::from_json(r#"{"name": "repeated", "tests": [
+            {"protocol": "tcp", "num_messages": 4, "message_len": 8, "repetitions": 3, "label": "small"},
+            {"protocol": "udp", "num_messages": 2, "message_len": 16, "repetitions": 2}
+        ]}"#).unwrap();
+        let labels: Vec<String> = suite.expand().into_iter().map(|(label, _)| label).collect();
+        assert_eq!(labels, vec!["small", "small", "small", "udp:2:16", "udp:2:16"]);
+        assert_eq!(suite.expand()[3].1.protocol(), Protocol::Udp);
+    }
+
+    #[test]
+    fn rejects_zero_repetitions() {
+        let suite = Suite::from_json(r#"{"name": "none", "tests": [{"protocol": "tcp", "num_messages": 4, "message_len": 8, "repetitions": 0}]}"#);
+        assert!(matches!(suite, Err(SuiteError::Invalid(_))));
+    }
+}
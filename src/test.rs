@@ -1,60 +1,654 @@
-use std::io;
+use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
-use std::ops::{ Div, Add };
+
+use error::{ DroppedMessage, FailureCause, TestError };
+use probe::PROBE_HEADER_LEN;
+use control::ServerStats;
+use stats::{ self, Histogram, LatencyStats };
+
+/// The largest payload that fits in a single UDP datagram.
+pub const MAX_UDP_MESSAGE_LEN: usize = 65507;
+
+/// The transport protocol a test uses.
+#[derive(Serialize, Deserialize, Debug, Hash, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s.to_lowercase().as_str() {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            _ => Err(format!("'{}' is not a valid protocol (tcp or udp only).", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+/// Which way the data of a bulk TCP test flows.
+#[derive(Serialize, Deserialize, Debug, Hash, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The client streams to the echo server, which echoes it back
+    #[default]
+    Echo,
+    /// The echo server streams to the client
+    Reverse,
+    /// The client and the echo server stream to each other at the same time
+    Bidirectional,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Direction, String> {
+        match s.to_lowercase().as_str() {
+            "echo" => Ok(Direction::Echo),
+            "reverse" => Ok(Direction::Reverse),
+            "bidirectional" => Ok(Direction::Bidirectional),
+            _ => Err(format!("'{}' is not a valid direction (echo, reverse or bidirectional only).", s)),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Echo => write!(f, "echo"),
+            Direction::Reverse => write!(f, "reverse"),
+            Direction::Bidirectional => write!(f, "bidirectional"),
+        }
+    }
+}
 
 /// A web test that should use either a TCP/IP connection or a UDP connection. Both contain a
 /// TestSpec struct that has specifications for the test.
-#[derive(Serialize, Deserialize, Debug, Hash)]
+#[derive(Serialize, Deserialize, Debug, Hash, Clone)]
 pub enum Test {
     UdpTest(TestSpec),
     TcpTest(TestSpec)
 }
 
+impl Test {
+    pub fn new(protocol: Protocol, spec: TestSpec) -> Test {
+        match protocol {
+            Protocol::Udp => Test::UdpTest(spec),
+            Protocol::Tcp => Test::TcpTest(spec),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match *self {
+            Test::UdpTest(_) => Protocol::Udp,
+            Test::TcpTest(_) => Protocol::Tcp,
+        }
+    }
+
+    /// The test as it's actually ran. UDP messages shorter than the probe header are padded to its
+    /// length, so the test is reported as sending messages that long.
+    pub fn padded(self) -> Test {
+        match self {
+            Test::UdpTest(spec) => Test::UdpTest(TestSpec { message_len: spec.datagram_len(), ..spec }),
+            tcp => tcp,
+        }
+    }
+
+    pub fn spec(&self) -> &TestSpec {
+        match *self {
+            Test::UdpTest(ref spec) | Test::TcpTest(ref spec) => spec,
+        }
+    }
+
+    /// Checks that the test can actually be ran, returning a description of the problem if not.
+    pub fn validate(&self) -> Result<(), String> {
+        let spec = self.spec();
+        if spec.num_messages == 0 && !spec.is_bulk() {
+            return Err("the number of messages must be greater than zero".to_string())
+        }
+        if spec.message_len == 0 {
+            return Err("the message length must be greater than zero".to_string())
+        }
+        if self.protocol() == Protocol::Udp && spec.message_len > MAX_UDP_MESSAGE_LEN {
+            return Err(format!("UDP messages can be at most {} bytes long", MAX_UDP_MESSAGE_LEN))
+        }
+        if spec.timeout_ms == Some(0) {
+            return Err("the timeout must be greater than zero".to_string())
+        }
+        if spec.window.is_some() && self.protocol() != Protocol::Udp {
+            return Err("a window only applies to UDP tests".to_string())
+        }
+        if spec.window == Some(0) {
+            return Err("the window must be greater than zero".to_string())
+        }
+        if (spec.rate_bps.is_some() || spec.rate_pps.is_some()) && self.protocol() != Protocol::Udp {
+            return Err("a send rate only applies to UDP tests".to_string())
+        }
+        if spec.rate_bps.is_some() && spec.rate_pps.is_some() {
+            return Err("the send rate can be given in bits or messages per second, but not both".to_string())
+        }
+        if spec.rate_bps == Some(0) || spec.rate_pps == Some(0) {
+            return Err("the send rate must be greater than zero".to_string())
+        }
+        if spec.is_bulk() && self.protocol() != Protocol::Tcp {
+            return Err("a duration or byte count only applies to TCP tests".to_string())
+        }
+        if spec.duration_ms == Some(0) || spec.total_bytes == Some(0) {
+            return Err("the duration and byte count must be greater than zero".to_string())
+        }
+        if spec.interval_ms.is_some() && !spec.is_bulk() {
+            return Err("a sample interval only applies to tests with a duration or byte count".to_string())
+        }
+        if spec.interval_ms == Some(0) {
+            return Err("the sample interval must be greater than zero".to_string())
+        }
+        if spec.direction != Direction::Echo && !(spec.is_bulk() && self.protocol() == Protocol::Tcp) {
+            return Err("only TCP tests with a duration or byte count can be reverse or bidirectional".to_string())
+        }
+        if spec.connect_per_message && (self.protocol() != Protocol::Tcp || spec.is_bulk()) {
+            return Err("only TCP tests of separate messages can open a connection for every message".to_string())
+        }
+        if spec.parallel == Some(0) {
+            return Err("the number of parallel streams must be greater than zero".to_string())
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Test {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let spec = self.spec();
+        write!(f, "{}:{}:{}", self.protocol(), spec.num_messages, spec.message_len)
+    }
+}
+
 /// A struct that has specifications for a test to follow.
-#[derive(Hash, Debug, Serialize, Deserialize)]
+#[derive(Hash, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct TestSpec {
     /// The number of messages that should be sent
     pub num_messages: u32,
     /// The length of the message that should be sent
     pub message_len: usize,
+    /// How long to wait for each message to be echoed, in milliseconds. If this is None the
+    /// configured timeout is used.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// How many messages a UDP test keeps in flight at once. If this is None each message is only
+    /// sent once the previous one has been echoed (or timed out).
+    #[serde(default)]
+    pub window: Option<u32>,
+    /// Send the messages of a UDP test at this many bits per second, counting only the UDP
+    /// payload, instead of as fast as they're echoed.
+    #[serde(default)]
+    pub rate_bps: Option<u64>,
+    /// Send the messages of a UDP test at this many messages per second, instead of as fast as
+    /// they're echoed.
+    #[serde(default)]
+    pub rate_pps: Option<u64>,
+    /// Make a TCP test a bulk test that streams data for this many milliseconds, instead of
+    /// echoing [num_messages] separate messages.
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    /// Make a TCP test a bulk test that streams this many bytes, instead of echoing
+    /// [num_messages] separate messages.
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+    /// How often a bulk test samples its throughput, in milliseconds. Defaults to a second.
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+    /// Run the test over this many connections (or sockets) at once.
+    #[serde(default)]
+    pub parallel: Option<u32>,
+    /// Which way a bulk TCP test streams: through the echo server and back, from the echo server
+    /// to the client, or both ways at once.
+    #[serde(default)]
+    pub direction: Direction,
+    /// Open a new connection for every message of a TCP test, instead of sending every message
+    /// over one connection, to measure how long connecting and closing take.
+    #[serde(default)]
+    pub connect_per_message: bool,
+}
+
+impl TestSpec {
+    /// How long each datagram of a UDP test is, since it has to fit the probe header.
+    pub fn datagram_len(&self) -> usize {
+        self.message_len.max(PROBE_HEADER_LEN)
+    }
+
+    /// The number of messages per second a paced UDP test aims for, if it's paced.
+    pub fn target_pps(&self) -> Option<f64> {
+        match (self.rate_pps, self.rate_bps) {
+            (Some(pps), _) => Some(pps as f64),
+            (None, Some(bps)) => Some(bps as f64 / (self.datagram_len() * 8) as f64),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether this is a bulk TCP test, which streams data through the echo server in chunks of
+    /// [message_len] bytes and ignores [num_messages].
+    pub fn is_bulk(&self) -> bool {
+        self.duration_ms.is_some() || self.total_bytes.is_some()
+    }
+
+    /// How long a paced UDP test waits between sending each message.
+    pub fn send_interval(&self) -> Option<Duration> {
+        self.target_pps().map(|pps| Duration::from_secs_f64(1.0 / pps))
+    }
 }
 
 /// Return type for a Test being ran
-pub type TestResult = Result<TestData, Option<io::Error>>;
+pub type TestResult = Result<TestData, TestError>;
 
 
 /// A structure containing data about a test that ran.
 #[derive(Hash, Debug, Serialize, Deserialize)]
 pub struct TestData {
-    /// The amount of time the test took to finish
+    /// The amount of time the test took to finish, from sending its first message to receiving
+    /// the echo of its last (or giving up on it)
     pub total_duration: Duration,
 
-    /// The amount of time each test took to finish in nanoseconds
+    /// The round trip time of each message, None for messages that were dropped
     pub individual_durations: Vec<Option<Duration>>,
 
     /// The spec the test followed
     pub test: Test,
 
-    /// The messages that were dropped. The values correspond to the message number
-    /// that was dropped.
-    pub dropped_messages: Vec<u32>
+    /// The messages that were dropped, along with why each one was dropped.
+    pub dropped_messages: Vec<DroppedMessage>,
+
+    /// The label of the test, if it was ran as part of a suite
+    #[serde(default)]
+    pub label: Option<String>,
+
+    /// How the echoes of a UDP test arrived. Empty for TCP tests.
+    #[serde(default)]
+    pub datagrams: DatagramClasses,
+
+    /// How long it took to send every message of a windowed or paced UDP test, which is used to
+    /// find the rate it actually sent at.
+    #[serde(default)]
+    pub send_duration: Option<Duration>,
+
+    /// How much data a bulk TCP test received: the echo of what it sent, or what the echo server
+    /// streamed to it for reverse and bidirectional tests.
+    #[serde(default)]
+    pub throughput: Option<Throughput>,
+
+    /// How much data a bidirectional test streamed to the echo server, while receiving
+    /// [throughput].
+    #[serde(default)]
+    pub sent_throughput: Option<Throughput>,
+
+    /// What the echo server saw of the test.
+    #[serde(default)]
+    pub server_stats: Option<ServerStats>,
+
+    /// How long each step of the connection of every message took, for tests that open a
+    /// connection for every message. None for messages that were dropped.
+    #[serde(default)]
+    pub connections: Vec<Option<ConnectionTimes>>,
+
+    /// The results of each stream of a parallel test, which this combines.
+    #[serde(default)]
+    pub streams: Vec<TestData>,
+}
+
+/// What a test achieved overall. Every output format reports these, so they're computed in one
+/// place.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// How long the whole test took, which for a parallel test is its longest stream
+    pub wall_clock: Duration,
+    /// How many messages the test tried to send
+    pub messages: usize,
+    /// How many of them were echoed intact
+    pub successful: usize,
+    /// The mean round trip time of the messages that were echoed, None if none were
+    pub mean_rtt: Option<Duration>,
+    /// How many bytes of the messages that were echoed came back, or how many bytes a bulk test
+    /// received
+    pub bytes_echoed: u64,
+    /// [bytes_echoed] per second of [wall_clock], None if the test took no time at all
+    pub throughput: Option<f64>,
+}
+
+/// The amount of data a bulk TCP test streamed in one direction, in total and over each interval.
+#[derive(Hash, Debug, Serialize, Deserialize, Clone)]
+pub struct Throughput {
+    pub bytes_sent: u64,
+    /// Bytes that arrived, all of which were checked against what was sent. For data the client
+    /// streams to the echo server without it being echoed, this is everything the echo server
+    /// read before closing the connection.
+    pub bytes_received: u64,
+    /// How long it took from sending the first byte to receiving the last one
+    pub duration: Duration,
+    pub interval: Duration,
+    /// How many bytes were received (or sent, for a bidirectional test's [TestData::sent_throughput])
+    /// during each interval since the start of the test
+    pub interval_bytes: Vec<u64>,
+}
+
+impl Throughput {
+    /// Adds this to [total], for streams that ran at the same time.
+    fn add_to(&self, total: &mut Option<Throughput>) {
+        let total = total.get_or_insert_with(|| Throughput {
+            bytes_sent: 0,
+            bytes_received: 0,
+            duration: Duration::new(0, 0),
+            interval: self.interval,
+            interval_bytes: vec![],
+        });
+        total.bytes_sent += self.bytes_sent;
+        total.bytes_received += self.bytes_received;
+        total.duration = total.duration.max(self.duration);
+        if total.interval_bytes.len() < self.interval_bytes.len() {
+            total.interval_bytes.resize(self.interval_bytes.len(), 0);
+        }
+        for (total_bytes, bytes) in total.interval_bytes.iter_mut().zip(self.interval_bytes.iter()) {
+            *total_bytes += *bytes;
+        }
+    }
+
+    /// The rate data was echoed at, in bits per second.
+    pub fn goodput_bps(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            secs if secs > 0.0 => (self.bytes_received * 8) as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// How long each interval was. The last one ends when the test does, so it's usually shorter
+    /// than the rest.
+    pub fn interval_lengths(&self) -> Vec<Duration> {
+        let last = self.interval_bytes.len().saturating_sub(1);
+        (0..self.interval_bytes.len())
+            .map(|i| if i == last { self.duration.saturating_sub(self.interval * i as u32) } else { self.interval })
+            .collect()
+    }
+
+    /// The rate data was echoed at during each interval, in bits per second.
+    pub fn interval_bps(&self) -> Vec<f64> {
+        self.interval_bytes.iter().zip(self.interval_lengths()).map(|(bytes, length)| {
+            match length.as_secs_f64() {
+                secs if secs > 0.0 => (bytes * 8) as f64 / secs,
+                _ => 0.0,
+            }
+        }).collect()
+    }
+}
+
+/// How long each step of the life of the connection a message was echoed over took, for tests that
+/// open a connection for every message.
+#[derive(Hash, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ConnectionTimes {
+    /// From starting to connect until the connection was established
+    pub connect: Duration,
+    /// From sending the message until the first byte of its echo arrived
+    pub first_byte: Duration,
+    /// From sending the message until all of its echo arrived
+    pub round_trip: Duration,
+    /// From shutting down the connection until the echo server closed its end
+    pub close: Duration,
+}
+
+/// The message numbers of the datagrams of a UDP test that didn't arrive normally. A message
+/// that arrived late is also in [TestData::dropped_messages], since it was given up on.
+#[derive(Hash, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DatagramClasses {
+    /// Echoes that arrived after the timeout
+    pub late: Vec<u32>,
+    /// Echoes that arrived after the echo of a message that was sent later
+    pub reordered: Vec<u32>,
+    /// Echoes of messages whose echo had already arrived
+    pub duplicated: Vec<u32>,
+    /// Messages that were sent but never echoed
+    pub lost: Vec<u32>,
 }
 
 impl TestData {
-    pub fn average_duration(&self) -> Duration {
-        let mut total = Duration::new(0, 0);
-        let mut num_messages = 0;
-        for i in self.individual_durations.iter() {
-            match i {
-                &Some(ref x) => {
-
-                    num_messages += 1;
-                    total = total.add(*x);
-                },
-                &None => {}
+    /// Results with only the messages of a test. Everything else a test can measure is left
+    /// empty, for the tests that measure it to fill in.
+    pub fn new(test: Test, total_duration: Duration, individual_durations: Vec<Option<Duration>>,
+               dropped_messages: Vec<DroppedMessage>) -> TestData {
+        TestData {
+            total_duration,
+            individual_durations,
+            test,
+            dropped_messages,
+            label: None,
+            datagrams: DatagramClasses::default(),
+            send_duration: None,
+            throughput: None,
+            sent_throughput: None,
+            server_stats: None,
+            connections: vec![],
+            streams: vec![],
+        }
+    }
+
+    /// Combines the results of every stream of a parallel [test]. Messages are numbered as if
+    /// every stream's messages were sent one stream after another, but the streams are assumed to
+    /// have ran at the same time, so the test took as long as its longest stream.
+    pub fn combine(test: Test, streams: Vec<TestData>) -> TestData {
+        let mut combined = TestData::new(test, Duration::new(0, 0), vec![], vec![]);
+
+        for stream in streams.iter() {
+            let offset = combined.individual_durations.len() as u32;
+            let renumber = |numbers: &Vec<u32>| numbers.iter().map(|n| n + offset).collect::<Vec<u32>>();
+
+            combined.total_duration = combined.total_duration.max(stream.total_duration);
+            combined.individual_durations.extend(stream.individual_durations.iter().cloned());
+            combined.dropped_messages.extend(stream.dropped_messages.iter().map(|dropped| DroppedMessage {
+                message_number: dropped.message_number + offset,
+                ..dropped.clone()
+            }));
+            combined.datagrams.late.extend(renumber(&stream.datagrams.late));
+            combined.datagrams.reordered.extend(renumber(&stream.datagrams.reordered));
+            combined.datagrams.duplicated.extend(renumber(&stream.datagrams.duplicated));
+            combined.datagrams.lost.extend(renumber(&stream.datagrams.lost));
+            combined.send_duration = combined.send_duration.max(stream.send_duration);
+            combined.connections.extend(stream.connections.iter().cloned());
+
+            if let Some(ref throughput) = stream.throughput {
+                throughput.add_to(&mut combined.throughput);
+            }
+            if let Some(ref throughput) = stream.sent_throughput {
+                throughput.add_to(&mut combined.sent_throughput);
             }
         }
-        total = total.div(num_messages);
-        total
+
+        combined.streams = streams;
+        combined
     }
-}
\ No newline at end of file
+
+    /// The overall results of the test.
+    pub fn summary(&self) -> Summary {
+        let messages = self.individual_durations.len();
+        let successful = self.individual_durations.iter().flatten().count();
+        let bytes_echoed = match self.throughput {
+            Some(ref throughput) => throughput.bytes_received,
+            None => (successful * self.test.spec().message_len) as u64,
+        };
+        let throughput = match self.total_duration.as_secs_f64() {
+            secs if secs > 0.0 => Some(bytes_echoed as f64 / secs),
+            _ => None,
+        };
+        Summary {
+            wall_clock: self.total_duration,
+            messages,
+            successful,
+            mean_rtt: self.average_duration(),
+            bytes_echoed,
+            throughput,
+        }
+    }
+
+    /// The round trip time of every message that was echoed, in the order they were sent. Empty
+    /// for bulk tests, which don't have separate messages.
+    pub fn round_trip_times(&self) -> Vec<Duration> {
+        self.individual_durations.iter().flatten().cloned().collect()
+    }
+
+    /// Summary statistics of [round_trip_times], or None if no message was echoed.
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        LatencyStats::new(&self.round_trip_times())
+    }
+
+    /// The average round trip time, or None if no message was echoed.
+    pub fn average_duration(&self) -> Option<Duration> {
+        self.latency_stats().map(|stats| stats.mean)
+    }
+
+    /// A histogram of the round trip times, with a bucket for every power of two nanoseconds.
+    pub fn histogram(&self) -> Histogram {
+        Histogram::new(&self.round_trip_times())
+    }
+
+    /// The average time each step of a connection took, over every message that was echoed, for
+    /// tests that open a connection for every message.
+    pub fn average_connection_times(&self) -> Option<ConnectionTimes> {
+        let connections: Vec<&ConnectionTimes> = self.connections.iter().flatten().collect();
+        if connections.is_empty() {
+            return None
+        }
+        let count = connections.len() as u32;
+        let average = |step: fn(&ConnectionTimes) -> Duration| connections.iter().map(|times| step(times)).sum::<Duration>() / count;
+        Some(ConnectionTimes {
+            connect: average(|times| times.connect),
+            first_byte: average(|times| times.first_byte),
+            round_trip: average(|times| times.round_trip),
+            close: average(|times| times.close),
+        })
+    }
+
+    /// How many messages were dropped because of [cause].
+    pub fn dropped_because(&self, cause: FailureCause) -> usize {
+        self.dropped_messages.iter().filter(|dropped| dropped.cause == cause).count()
+    }
+
+    /// How many messages were actually sent, which excludes those that failed to send.
+    pub fn messages_sent(&self) -> usize {
+        self.individual_durations.len() - self.dropped_because(FailureCause::Io)
+    }
+
+    /// The rate messages were actually sent at, in messages per second, if it is known.
+    pub fn achieved_pps(&self) -> Option<f64> {
+        match self.send_duration {
+            Some(duration) if duration > Duration::new(0, 0) => Some(self.messages_sent() as f64 / duration.as_secs_f64()),
+            _ => None,
+        }
+    }
+
+    /// The rate messages were actually sent at, in bits of UDP payload per second, if it is known.
+    pub fn achieved_bps(&self) -> Option<f64> {
+        self.achieved_pps().map(|pps| pps * (self.test.spec().datagram_len() * 8) as f64)
+    }
+
+    /// The rate a paced test aimed for, in bits of UDP payload per second.
+    pub fn target_bps(&self) -> Option<f64> {
+        self.test.spec().target_pps().map(|pps| pps * (self.test.spec().datagram_len() * 8) as f64)
+    }
+
+    /// The percentage of the messages that were sent that weren't echoed intact in time. This is
+    /// worked out from [dropped_messages] rather than [datagrams], so it's the same for TCP and
+    /// UDP tests and for results loaded from any format.
+    pub fn loss_percent(&self) -> f64 {
+        match self.messages_sent() {
+            0 => 0.0,
+            sent => (self.dropped_messages.len() - self.dropped_because(FailureCause::Io)) as f64 * 100.0 / sent as f64,
+        }
+    }
+
+    /// How much the round trip time varies between consecutive echoes (see [stats::jitter]), or
+    /// None if fewer than two messages were echoed.
+    pub fn jitter(&self) -> Option<Duration> {
+        stats::jitter(&self.round_trip_times())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data(message_len: usize, total_duration: Duration, durations: Vec<Option<Duration>>) -> TestData {
+        let dropped_messages = durations.iter().enumerate()
+            .filter(|&(_, duration)| duration.is_none())
+            .map(|(i, _)| DroppedMessage::new(i as u32, &TestError::Timeout))
+            .collect();
+        TestData::new(Test::TcpTest(TestSpec { num_messages: 4, message_len, ..TestSpec::default() }), total_duration, durations, dropped_messages)
+    }
+
+    #[test]
+    fn summary_only_averages_echoed_messages() {
+        let data = test_data(1000, Duration::from_secs(2), vec![Some(Duration::from_millis(10)), None,
+                                                                 Some(Duration::from_millis(30)), None]);
+        let summary = data.summary();
+        assert_eq!(summary.wall_clock, Duration::from_secs(2));
+        assert_eq!(summary.messages, 4);
+        assert_eq!(summary.successful, 2);
+        assert_eq!(summary.mean_rtt, Some(Duration::from_millis(20)));
+        assert_eq!(summary.bytes_echoed, 2000);
+        assert_eq!(summary.throughput, Some(1000.0));
+    }
+
+    #[test]
+    fn summary_of_a_test_that_dropped_every_message() {
+        let summary = test_data(1000, Duration::from_secs(1), vec![None, None]).summary();
+        assert_eq!(summary.successful, 0);
+        assert_eq!(summary.mean_rtt, None);
+        assert_eq!(summary.bytes_echoed, 0);
+        assert_eq!(summary.throughput, Some(0.0));
+    }
+
+    #[test]
+    fn summary_of_a_test_that_took_no_time() {
+        let summary = test_data(1000, Duration::new(0, 0), vec![]).summary();
+        assert_eq!(summary.messages, 0);
+        assert_eq!(summary.mean_rtt, None);
+        assert_eq!(summary.throughput, None);
+    }
+
+    #[test]
+    fn summary_of_a_bulk_test_counts_bytes_received() {
+        let mut data = test_data(1000, Duration::from_secs(4), vec![]);
+        data.throughput = Some(Throughput {
+            bytes_sent: 10_000,
+            bytes_received: 8_000,
+            duration: Duration::from_secs(4),
+            interval: Duration::from_secs(1),
+            interval_bytes: vec![2_000; 4],
+        });
+        let summary = data.summary();
+        assert_eq!(summary.messages, 0);
+        assert_eq!(summary.mean_rtt, None);
+        assert_eq!(summary.bytes_echoed, 8_000);
+        assert_eq!(summary.throughput, Some(2_000.0));
+    }
+
+    #[test]
+    fn combined_streams_take_as_long_as_the_longest() {
+        let streams = vec![test_data(100, Duration::from_secs(1), vec![Some(Duration::from_millis(5)), None]),
+                           test_data(100, Duration::from_secs(3), vec![Some(Duration::from_millis(15))])];
+        let combined = TestData::combine(streams[0].test.clone(), streams);
+        let summary = combined.summary();
+        assert_eq!(summary.wall_clock, Duration::from_secs(3));
+        assert_eq!(summary.messages, 3);
+        assert_eq!(summary.successful, 2);
+        assert_eq!(summary.mean_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(combined.dropped_messages[0].message_number, 1);
+        assert_eq!(summary.bytes_echoed, 200);
+    }
+}
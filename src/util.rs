@@ -1,21 +1,97 @@
-use test::*;
-use csv::Writer;
-use std::fs::File;
-use std::io;
+use std::fmt;
 use std::net::*;
+use std::str::FromStr;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+/// Only errors are printed.
+pub const VERBOSITY_QUIET: usize = 0;
+/// Errors and log messages are printed.
+pub const VERBOSITY_NORMAL: usize = 1;
+/// Everything is printed, including debug messages like every individual echo.
+pub const VERBOSITY_DEBUG: usize = 2;
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(VERBOSITY_NORMAL);
+
+pub fn set_verbosity(verbosity: usize) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
 
 pub fn create_address(s: &str) -> Result<SocketAddr, ()> {
-    let iter = s.to_socket_addrs();
-    if iter.is_err() {
-        Err(())
-    } else if let Some(x) = iter.unwrap().next() {
-        Ok(x)
-    } else {
-        Err(())
+    match s.to_socket_addrs() {
+        Ok(mut iter) => iter.next().ok_or(()),
+        Err(_) => Err(()),
+    }
+}
+
+/// An IP network in CIDR notation, like `129.3.0.0/16`. A plain address is treated as a network
+/// containing only that address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr = addr.trim().parse::<IpAddr>().map_err(|_| format!("'{}' is not a valid IP address", addr))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.trim().parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("'{}' is not a valid prefix length for {}", len, addr)),
+            },
+            None => max_len,
+        };
+        Ok(Network { addr, prefix_len })
     }
 }
 
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Prints a message of the form `[subject] key: value`. Messages with the subject ERR are always
+/// printed, DBG messages are only printed at [VERBOSITY_DEBUG], and everything else is printed
+/// unless the verbosity is [VERBOSITY_QUIET].
 pub fn pretty_print(subject: &str, key: &str, value: &str, same_line: bool) {
+    let required_verbosity = match subject {
+        "ERR" => VERBOSITY_QUIET,
+        "DBG" => VERBOSITY_DEBUG,
+        _ => VERBOSITY_NORMAL,
+    };
+    if VERBOSITY.load(Ordering::Relaxed) < required_verbosity {
+        return
+    }
+
     if same_line {
         println!("\u{001b}[1A[F\r[\u{001b}[32;1m{:<3}\u{001b}[0m] {:<16}: {}", subject, key, value)
     } else {
@@ -23,62 +99,48 @@ pub fn pretty_print(subject: &str, key: &str, value: &str, same_line: bool) {
     }
 }
 
-pub fn save_data_as_csv<S: Into<String>>(data: Vec<TestData>, filename: S) -> Result<(), io::Error> {
-    let filename = filename.into();
-    let file = File::create(filename)?;
-
-    let mut writer = Writer::from_writer(file);
-    // Test averages
-    writer.write_record(&["Transfer Protocall",
-                                "number of messages",
-                                "data size (bytes)",
-                                "average time (s)",
-                                "average throughput (bytes / sec)",
-                                "dropped messages"])?;
-
-    for mut test in data.iter() {
-        let (data_type, data_size) = match &test.test {
-            &Test::UdpTest(ref spec) => ("udp", spec.message_len),
-            &Test::TcpTest(ref spec) => ("tcp", spec.message_len),
-        };
-
-        let number_of_messages = test.individual_durations.len();
-
-        let average_time = test.average_duration();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let average_time_double = (average_time.as_secs() as f64) + (average_time.subsec_nanos() as f64 / 1_000_000_000.0f64);
-
-        let dropped_messages = test.dropped_messages.len();
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
 
-        writer.write_record(&[data_type,
-                                    &number_of_messages.to_string(),
-                                    &data_size.to_string(),
-                                    &average_time_double.to_string(),
-                                    // Calculate through put by calculating (messages_sent * message_size) / (average_time * messages_sent)
-                                    &(data_size as f64 / average_time_double).to_string(),
-                                    &dropped_messages.to_string()])?;
-    };
+    #[test]
+    fn network_contains_addresses_under_its_prefix() {
+        let network: Network = "129.3.0.0/16".parse().unwrap();
+        assert!(network.contains(ip("129.3.20.24")));
+        assert!(!network.contains(ip("129.4.0.1")));
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains(ip("8.8.8.8")));
 
-    // Individual data points
-    writer.write_record(&["Transfer Protocall", "data size (bytes)", "time (s)", "throughput (bytes / s)", "", ""])?;
-    for test in data.iter() {
-        let (data_type, data_size_string, data_size) = match &test.test {
-            &Test::UdpTest(ref spec) => ("udp", spec.message_len.to_string(), spec.message_len),
-            &Test::TcpTest(ref spec) => ("tcp", spec.message_len.to_string(), spec.message_len),
-        };
+        let network: Network = "fe80::/10".parse().unwrap();
+        assert!(network.contains(ip("fe80::1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+        assert!(!network.contains(ip("129.3.20.24")));
+    }
 
-        for dur in test.individual_durations.iter() {
-            if let Some(duration) = *dur {
-                let dur_double = (duration.as_secs() as f64) + (duration.subsec_nanos() as f64 / 1_000_000_000.0f64);
-                writer.write_record(&[data_type,
-                                            &data_size_string,
-                                            &dur_double.to_string(),
-                                            &(data_size as f64 / dur_double).to_string(), "", ""])?;
-            }
-        }
+    #[test]
+    fn network_of_a_plain_address_only_contains_it() {
+        let network: Network = "127.0.0.1".parse().unwrap();
+        assert_eq!(network.to_string(), "127.0.0.1/32");
+        assert!(network.contains(ip("127.0.0.1")));
+        assert!(!network.contains(ip("127.0.0.2")));
     }
 
-    writer.flush()?;
+    #[test]
+    fn network_contains_ipv4_mapped_ipv6_addresses() {
+        let network: Network = "129.3.0.0/16".parse().unwrap();
+        assert!(network.contains(ip("::ffff:129.3.1.1")));
+        assert!(!network.contains(ip("::ffff:10.0.0.1")));
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("::/129".parse::<Network>().is_err());
+        assert!("10.0.0.0/x".parse::<Network>().is_err());
+        assert!("10.0.0.256/8".parse::<Network>().is_err());
+        assert!("::/128".parse::<Network>().is_ok());
+    }
+}
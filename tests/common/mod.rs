@@ -0,0 +1,77 @@
+//! The echo server the integration tests and benchmarks run against, started as `dl1 echo` on
+//! loopback.
+
+// Not every test or benchmark uses every part of it
+#![allow(dead_code)]
+
+use std::net::{ TcpListener, TcpStream, UdpSocket };
+use std::process::{ Child, Command, Stdio };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Finds a port on loopback that nothing is bound to, for both TCP and UDP.
+pub fn free_port() -> u16 {
+    loop {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        if UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            return port
+        }
+    }
+}
+
+/// The echo server, which is killed when this is dropped.
+pub struct EchoServer {
+    child: Child,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+}
+
+impl EchoServer {
+    /// Starts the echo server on free ports with the settings in [env], and waits until it's
+    /// listening.
+    pub fn start(env: &[(&str, String)]) -> EchoServer {
+        let tcp_port = free_port();
+        let udp_port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_dl1"))
+            .args(["--quiet", "echo", "--no-stdin"])
+            .env("DL1_ECHO_SERVER_TCP_IP", format!("127.0.0.1:{}", tcp_port))
+            .env("DL1_ECHO_SERVER_UDP_IP", format!("127.0.0.1:{}", udp_port))
+            .envs(env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start the echo server");
+
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", tcp_port)).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10), "the echo server never started listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+        EchoServer { child, tcp_port, udp_port }
+    }
+
+    /// `dl1`, set up to run tests against the echo server.
+    pub fn client(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_dl1"));
+        command.arg("--quiet")
+            .env("DL1_UDP_IP", "127.0.0.1:0")
+            .env("DL1_ECHO_SERVER_TCP_IP", format!("127.0.0.1:{}", self.tcp_port))
+            .env("DL1_ECHO_SERVER_UDP_IP", format!("127.0.0.1:{}", self.udp_port))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        command
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", self.tcp_port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        stream
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,58 @@
+//! Runs `dl1 echo` over loopback and checks that everything sent to it comes back unchanged, even
+//! when messages are far larger than the socket buffers so the echo server can only write part of
+//! each one at a time.
+
+mod common;
+
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::thread;
+
+use common::EchoServer;
+
+/// A message where no byte is likely to line up with the same byte of another message.
+fn message(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 31 + seed * 7 + i / 251) % 256) as u8).collect()
+}
+
+/// Writes every message from another thread while reading the echo, since the echo server stops
+/// reading once it can't write, and checks the echo matches byte for byte.
+fn echo_messages(stream: TcpStream, messages: Vec<Vec<u8>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let sent = messages.clone();
+    let write = thread::spawn(move || {
+        for message in sent.iter() {
+            writer.write_all(message).unwrap();
+        }
+    });
+
+    let mut reader = stream;
+    for (i, message) in messages.iter().enumerate() {
+        let mut echo = vec![0u8; message.len()];
+        reader.read_exact(&mut echo).unwrap();
+        if let Some(offset) = echo.iter().zip(message.iter()).position(|(a, b)| a != b) {
+            panic!("message #{} differs from its echo at byte {}", i, offset);
+        }
+    }
+    write.join().unwrap();
+}
+
+#[test]
+fn echoes_multi_megabyte_messages_exactly() {
+    let server = EchoServer::start(&[("DL1_ECHO_TCP_CHUNK_SIZE", (64 * 1024).to_string())]);
+    let messages = (0..4).map(|i| message(i, 8 * 1024 * 1024 + i)).collect();
+    echo_messages(server.connect(), messages);
+}
+
+#[test]
+fn echoes_exactly_with_small_chunks_and_concurrent_clients() {
+    let server = EchoServer::start(&[("DL1_ECHO_TCP_CHUNK_SIZE", 1024.to_string())]);
+    let clients: Vec<_> = (0..4).map(|client| {
+        let stream = server.connect();
+        let messages = (0..2).map(|i| message(client * 2 + i, 3 * 1024 * 1024 + 17)).collect();
+        thread::spawn(move || echo_messages(stream, messages))
+    }).collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
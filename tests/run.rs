@@ -0,0 +1,112 @@
+//! Runs `dl1 test` against `dl1 echo` over loopback, and checks the results it saves against what
+//! was sent and what the echo server says it saw.
+
+extern crate serde_json;
+
+mod common;
+
+use std::env;
+use std::fs::{ self, File };
+use std::process;
+
+use serde_json::Value;
+
+use common::EchoServer;
+
+/// Runs `dl1 test` with [args] against [server], and returns the results of every test from the
+/// JSON document it saves. [name] keeps the file apart from those of other tests.
+fn run(server: &EchoServer, name: &str, args: &[&str]) -> Vec<Value> {
+    let path = env::temp_dir().join(format!("dl1-run-{}-{}.json", process::id(), name));
+    let status = server.client()
+        .arg("test")
+        .args(args)
+        .arg("--output").arg(&path)
+        .args(["--format", "json"])
+        .status()
+        .expect("failed to run dl1 test");
+    assert!(status.success(), "dl1 test {:?} exited with {}", args, status);
+
+    let document: Value = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+    let _ = fs::remove_file(&path);
+    document["tests"].as_array().unwrap().iter().map(|record| {
+        assert!(record["error"].is_null(), "a test failed: {}", record["error"]);
+        record["data"].clone()
+    }).collect()
+}
+
+/// How many messages of [data] were echoed.
+fn echoed(data: &Value) -> usize {
+    data["individual_durations"].as_array().unwrap().iter().filter(|rtt| !rtt.is_null()).count()
+}
+
+#[test]
+fn echoes_tcp_and_udp_messages() {
+    let server = EchoServer::start(&[]);
+    let results = run(&server, "echo", &["tcp:20:512", "udp:20:100"]);
+
+    let tcp = &results[0];
+    assert_eq!(echoed(tcp), 20);
+    assert_eq!(tcp["dropped_messages"].as_array().unwrap().len(), 0);
+    // Every message carries a 12 byte frame header
+    assert_eq!(tcp["server_stats"]["tcp_connections"], 1);
+    assert_eq!(tcp["server_stats"]["tcp_bytes_received"], 20 * (512 + 12));
+    assert_eq!(tcp["server_stats"]["tcp_bytes_sent"], 20 * (512 + 12));
+
+    let udp = &results[1];
+    assert_eq!(echoed(udp), 20);
+    assert_eq!(udp["server_stats"]["datagrams_received"], 20);
+    assert_eq!(udp["server_stats"]["datagrams_echoed"], 20);
+    assert_eq!(udp["server_stats"]["udp_bytes_echoed"], 20 * 100);
+}
+
+#[test]
+fn keeps_a_window_of_udp_messages_in_flight_at_a_paced_rate() {
+    let server = EchoServer::start(&[]);
+    let results = run(&server, "window", &["udp:200:64", "--window", "16", "--pps", "4000"]);
+
+    let udp = &results[0];
+    assert_eq!(echoed(udp), 200);
+    assert!(!udp["send_duration"].is_null());
+    assert_eq!(udp["server_stats"]["datagrams_echoed"], 200);
+}
+
+#[test]
+fn streams_from_the_echo_server_in_reverse() {
+    let server = EchoServer::start(&[]);
+    let results = run(&server, "reverse", &["tcp:1:4096", "--bytes", "1000000", "--direction", "reverse"]);
+
+    let reverse = &results[0];
+    assert_eq!(reverse["throughput"]["bytes_received"], 1_000_000);
+    assert_eq!(reverse["server_stats"]["tcp_bytes_sent"], 1_000_000);
+    assert_eq!(reverse["server_stats"]["tcp_bytes_received"], 0);
+}
+
+#[test]
+fn combines_the_streams_of_a_parallel_test() {
+    let server = EchoServer::start(&[]);
+    let results = run(&server, "parallel", &["tcp:10:256", "udp:10:64", "--parallel", "3"]);
+
+    let tcp = &results[0];
+    assert_eq!(tcp["streams"].as_array().unwrap().len(), 3);
+    assert_eq!(echoed(tcp), 30);
+    assert_eq!(tcp["server_stats"]["tcp_connections"], 3);
+    assert_eq!(tcp["server_stats"]["tcp_bytes_received"], 30 * (256 + 12));
+
+    let udp = &results[1];
+    assert_eq!(udp["streams"].as_array().unwrap().len(), 3);
+    assert_eq!(echoed(udp), 30);
+    assert_eq!(udp["server_stats"]["datagrams_echoed"], 30);
+}
+
+#[test]
+fn connects_for_every_message() {
+    let server = EchoServer::start(&[]);
+    let results = run(&server, "connect", &["tcp:5:128", "--connect-per-message"]);
+
+    let tcp = &results[0];
+    assert_eq!(echoed(tcp), 5);
+    let connections = tcp["connections"].as_array().unwrap();
+    assert_eq!(connections.len(), 5);
+    assert!(connections.iter().all(|times| !times.is_null()));
+    assert_eq!(tcp["server_stats"]["tcp_connections"], 5);
+}